@@ -0,0 +1,25 @@
+//! Mock SlimeVR server that prints every packet it receives.
+//!
+//! `cargo run -p protocol --features mock --bin mock-server -- [address] [--bundles]`
+use std::{env, thread, time::Duration};
+
+use protocol::mock::MockServer;
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let address = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map_or("127.0.0.1:6969", |a| a.as_str());
+
+    let server = MockServer::bind(address)?;
+    server.set_bundles(args.iter().any(|a| a == "--bundles"));
+    println!("Mock SlimeVR server listening on {}", server.address());
+
+    loop {
+        for packet in server.take_packets() {
+            println!("{packet:?}");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
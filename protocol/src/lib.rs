@@ -1,12 +1,20 @@
 mod test_deku;
+mod types;
+pub use types::*;
+#[cfg(feature = "mock")]
+pub mod mock;
 
 pub use deku;
 
-use std::string::FromUtf8Error;
+use std::{fmt, string::FromUtf8Error};
 
-use deku::prelude::*;
+use deku::{
+    bitvec::{BitSlice, Msb0},
+    ctx::Endian,
+    prelude::*,
+};
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct SlimeQuaternion {
     pub i: f32,
@@ -52,7 +60,7 @@ mod nalgebra030_impls {
     impl_Nalgebra!();
 }
 
-#[derive(PartialEq, Eq, Debug, DekuRead, DekuWrite)]
+#[derive(PartialEq, Eq, Debug, Clone, DekuRead, DekuWrite)]
 #[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
 pub struct SlimeString {
     #[deku(update = "self.data.len()")]
@@ -76,7 +84,84 @@ impl SlimeString {
     }
 }
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+/// Bit set announcing optional protocol features, sent by both tracker and server. It has no
+/// length, it runs until the end of the packet and only has as many bytes as the sender knows
+/// flags for.
+#[derive(Debug, Clone, Default, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct FeatureFlags {
+    #[deku(bits_read = "deku::rest.len()")]
+    bits: Vec<u8>,
+}
+impl FeatureFlags {
+    /// Server flag: the server understands [`PacketType::Bundle`].
+    pub const SERVER_BUNDLE: u8 = 0;
+
+    /// Flags from their raw bytes, flag 0 being the lowest bit of the first byte.
+    pub fn from_bits(bits: Vec<u8>) -> Self {
+        Self { bits }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        let (byte, bit) = (flag as usize / 8, flag % 8);
+        self.bits.get(byte).map_or(false, |b| b & (1 << bit) != 0)
+    }
+    pub fn with(mut self, flag: u8) -> Self {
+        let (byte, bit) = (flag as usize / 8, flag % 8);
+        if self.bits.len() <= byte {
+            self.bits.resize(byte + 1, 0);
+        }
+        self.bits[byte] |= 1 << bit;
+        self
+    }
+}
+
+/// A single packet inside a [`PacketType::Bundle`], prefixed with its length.
+#[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub struct BundledPacket {
+    #[deku(update = "self.data.len()")]
+    len: u16,
+    #[deku(count = "len")]
+    data: Vec<u8>,
+}
+impl From<Vec<u8>> for BundledPacket {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            len: data.len() as _,
+            data,
+        }
+    }
+}
+impl BundledPacket {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// Size inside the bundle, including the length prefix.
+    pub fn size(&self) -> usize {
+        2 + self.data.len()
+    }
+}
+
+// Bundles have no count, they just run until the end of the datagram.
+// A zero length marks the end as well, since receive buffers are zero padded.
+fn read_bundled(
+    rest: &BitSlice<u8, Msb0>,
+) -> Result<(&BitSlice<u8, Msb0>, Vec<BundledPacket>), DekuError> {
+    let mut rest = rest;
+    let mut packets = Vec::new();
+    while rest.len() >= 16 {
+        let (new_rest, packet) = BundledPacket::read(rest, Endian::Big)?;
+        if packet.data.is_empty() {
+            break;
+        }
+        rest = new_rest;
+        packets.push(packet);
+    }
+    Ok((rest, packets))
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
 #[deku(type = "u32")]
 #[deku(endian = "big")]
 pub enum PacketType {
@@ -88,9 +173,9 @@ pub enum PacketType {
     #[deku(id = "3")]
     Handshake {
         packet_id: u64,
-        board: i32,
-        imu: i32,
-        mcu_type: i32,
+        board: BoardType,
+        imu: ImuType,
+        mcu_type: McuType,
         imu_info: (i32, i32, i32),
         build: i32,
         firmware: SlimeString,
@@ -104,12 +189,31 @@ pub enum PacketType {
     },
     #[deku(id = "10")]
     Ping { id: u32 },
+    #[deku(id = "12")]
+    BatteryLevel {
+        packet_id: u64,
+        voltage: f32,
+        /// 0.0 to 1.0
+        level: f32,
+    },
+    #[deku(id = "13")]
+    Tap {
+        packet_id: u64,
+        sensor_id: u8,
+        value: u8,
+    },
+    #[deku(id = "14")]
+    Error {
+        packet_id: u64,
+        sensor_id: u8,
+        error: u8,
+    },
     #[deku(id = "15")]
     SensorInfo {
         packet_id: u64,
         sensor_id: u8,
-        sensor_status: u8,
-        sensor_type: u8,
+        sensor_status: SensorStatus,
+        sensor_type: SensorType,
     },
     #[deku(id = "17")]
     RotationData {
@@ -119,8 +223,100 @@ pub enum PacketType {
         quat: SlimeQuaternion,
         calibration_info: u8,
     },
+    #[deku(id = "18")]
+    MagnetometerAccuracy {
+        packet_id: u64,
+        sensor_id: u8,
+        accuracy: f32,
+    },
+    #[deku(id = "19")]
+    SignalStrength {
+        packet_id: u64,
+        sensor_id: u8,
+        /// dBm
+        strength: i8,
+    },
+    #[deku(id = "20")]
+    Temperature {
+        packet_id: u64,
+        sensor_id: u8,
+        /// Degrees celsius
+        temperature: f32,
+    },
     #[deku(id = "21")]
-    UserAction { packet_id: u64, typ: u8 },
+    UserAction { packet_id: u64, typ: UserActionType },
+    #[deku(id = "22")]
+    FeatureFlags { packet_id: u64, flags: FeatureFlags },
+    #[deku(id = "100")]
+    Bundle {
+        packet_id: u64,
+        #[deku(reader = "read_bundled(deku::rest)")]
+        packets: Vec<BundledPacket>,
+    },
     #[deku(id = "55076217")] // u8 array with [3, 'H', 'e', 'y'] as u32
     HandshakeResponse,
 }
+
+/// Packets sent from the SlimeVR server to a tracker.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(type = "u32")]
+#[deku(endian = "big")]
+pub enum ServerPacket {
+    #[deku(id = "1")]
+    Heartbeat,
+    #[deku(id = "2")]
+    Vibrate,
+    #[deku(id = "4")]
+    Command { command: u8 },
+    #[deku(id = "10")]
+    Ping { id: u32 },
+    #[deku(id = "15")]
+    SensorInfo {
+        sensor_id: u8,
+        sensor_status: SensorStatus,
+    },
+    #[deku(id = "22")]
+    FeatureFlags { packet_id: u64, flags: FeatureFlags },
+    #[deku(id = "55076217")] // u8 array with [3, 'H', 'e', 'y'] as u32
+    HandshakeResponse,
+    #[deku(id_pat = "_")]
+    Unknown(u32),
+}
+impl ServerPacket {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        match Self::from_bytes((data, 0)) {
+            Ok((_, Self::Unknown(id))) => Err(DecodeError::UnknownId(id)),
+            Ok((_, packet)) => Ok(packet),
+            Err(e) => Err(DecodeError::Invalid(e)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    UnknownId(u32),
+    Invalid(DekuError),
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownId(id) => write!(f, "unknown packet id {id}"),
+            DecodeError::Invalid(e) => write!(f, "invalid packet: {e}"),
+        }
+    }
+}
+impl std::error::Error for DecodeError {}
+
+impl PacketType {
+    /// Serializes the packet the way it is stored inside a bundle, which is without packet number.
+    pub fn to_bundled(&self) -> Result<BundledPacket, DekuError> {
+        let mut bytes = self.to_bytes()?;
+        if !matches!(
+            self,
+            PacketType::Ping { .. } | PacketType::HandshakeResponse
+        ) {
+            bytes.drain(4..12);
+        }
+        Ok(bytes.into())
+    }
+}
@@ -0,0 +1,197 @@
+//! A minimal SlimeVR server for tests and local debugging.
+//!
+//! It answers handshakes, pings the tracker every second and records every packet it receives,
+//! with bundles unpacked into their inner packets.
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use deku::{DekuContainerRead, DekuContainerWrite};
+
+use crate::{BundledPacket, FeatureFlags, PacketType, SensorStatus, ServerPacket};
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct State {
+    packets: Vec<PacketType>,
+    tracker: Option<SocketAddr>,
+}
+
+pub struct MockServer {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    online: Arc<AtomicBool>,
+    bundles: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Starts a server on a random localhost port.
+    pub fn start() -> io::Result<Self> {
+        Self::bind("127.0.0.1:0")
+    }
+
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+
+        let mut server = Self {
+            address: socket.local_addr()?,
+            state: Arc::default(),
+            online: Arc::new(AtomicBool::new(true)),
+            bundles: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        };
+        let (state, online, bundles, stop) = (
+            server.state.clone(),
+            server.online.clone(),
+            server.bundles.clone(),
+            server.stop.clone(),
+        );
+        server.thread = Some(thread::spawn(move || {
+            run(&socket, &state, &online, &bundles, &stop);
+        }));
+        Ok(server)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// While offline the server ignores everything and stops pinging, like a server that was closed.
+    pub fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::SeqCst);
+    }
+
+    /// Whether the server announces bundle support in its feature flags.
+    pub fn set_bundles(&self, bundles: bool) {
+        self.bundles.store(bundles, Ordering::SeqCst);
+    }
+
+    /// Every packet received so far.
+    pub fn packets(&self) -> Vec<PacketType> {
+        self.state.lock().unwrap().packets.clone()
+    }
+
+    /// Removes and returns every packet received so far.
+    pub fn take_packets(&self) -> Vec<PacketType> {
+        std::mem::take(&mut self.state.lock().unwrap().packets)
+    }
+
+    /// Waits until a packet matching `predicate` has been received.
+    pub fn wait_for<F>(&self, timeout: Duration, predicate: F) -> Option<PacketType>
+    where
+        F: Fn(&PacketType) -> bool,
+    {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(packet) = self
+                .state
+                .lock()
+                .unwrap()
+                .packets
+                .iter()
+                .find(|p| predicate(p))
+            {
+                return Some(packet.clone());
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn run(
+    socket: &UdpSocket,
+    state: &Mutex<State>,
+    online: &AtomicBool,
+    bundles: &AtomicBool,
+    stop: &AtomicBool,
+) {
+    let mut buf = [0; 2048];
+    let mut last_ping = Instant::now();
+    let mut ping_id = 0u32;
+
+    while !stop.load(Ordering::SeqCst) {
+        let received = socket.recv_from(&mut buf);
+        if !online.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        if let Ok((len, from)) = received {
+            let Ok((_, packet)) = PacketType::from_bytes((&buf[..len], 0)) else {
+                continue;
+            };
+            let reply = match &packet {
+                PacketType::Handshake { .. } => Some(ServerPacket::HandshakeResponse),
+                PacketType::SensorInfo { sensor_id, .. } => Some(ServerPacket::SensorInfo {
+                    sensor_id: *sensor_id,
+                    sensor_status: SensorStatus::Ok,
+                }),
+                PacketType::FeatureFlags { .. } => {
+                    // The SlimeVR server always replies with a single byte of flags
+                    let mut flags = 0u8;
+                    if bundles.load(Ordering::SeqCst) {
+                        flags |= 1 << FeatureFlags::SERVER_BUNDLE;
+                    }
+                    Some(ServerPacket::FeatureFlags {
+                        packet_id: 0,
+                        flags: FeatureFlags::from_bits(vec![flags]),
+                    })
+                }
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                socket.send_to(&reply.to_bytes().unwrap(), from).ok();
+            }
+
+            let mut state = state.lock().unwrap();
+            state.tracker = Some(from);
+            if let PacketType::Bundle { packets, .. } = &packet {
+                state.packets.extend(packets.iter().filter_map(unbundle));
+            }
+            state.packets.push(packet);
+        }
+
+        let tracker = state.lock().unwrap().tracker;
+        if let Some(tracker) = tracker {
+            if last_ping.elapsed() >= PING_INTERVAL {
+                last_ping = Instant::now();
+                ping_id = ping_id.wrapping_add(1);
+                let ping = ServerPacket::Ping { id: ping_id };
+                socket.send_to(&ping.to_bytes().unwrap(), tracker).ok();
+            }
+        }
+    }
+}
+
+// Bundled packets are stored without packet number, put an empty one back to decode them.
+fn unbundle(bundled: &BundledPacket) -> Option<PacketType> {
+    let data = bundled.data();
+    if data.len() < 4 {
+        return None;
+    }
+    let mut bytes = data[..4].to_vec();
+    bytes.extend([0; 8]);
+    bytes.extend(&data[4..]);
+    PacketType::from_bytes((&bytes, 0)).ok().map(|(_, p)| p)
+}
@@ -3,16 +3,19 @@ mod tests {
     use deku::{DekuContainerRead, DekuContainerWrite};
     use nalgebra032::{Quaternion, UnitQuaternion};
 
-    use crate::PacketType;
+    use crate::{
+        BoardType, BundledPacket, DecodeError, FeatureFlags, ImuType, McuType, PacketType,
+        SensorStatus, SensorType, ServerPacket, UserActionType,
+    };
 
     #[test]
     fn handshake() {
         let mac: [u8; 6] = [121, 34, 164, 250, 231, 204]; // test mac
         let handshake = PacketType::Handshake {
             packet_id: 1,
-            board: 2,
-            imu: 3,
-            mcu_type: 4,
+            board: BoardType::SlimevrDev,
+            imu: ImuType::Bno080,
+            mcu_type: McuType::Wrangler,
             imu_info: (5, 6, 7),
             build: 8,
             firmware: "test".to_string().into(),
@@ -24,6 +27,7 @@ mod tests {
         ];
 
         assert_eq!(handshake.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, handshake);
     }
     #[test]
     fn quat() {
@@ -44,13 +48,14 @@ mod tests {
         let sensor_info = PacketType::SensorInfo {
             packet_id: 1,
             sensor_id: 64,
-            sensor_status: 3,
-            sensor_type: 5,
+            sensor_status: SensorStatus::Unknown(3),
+            sensor_type: SensorType::Bno055,
         };
 
         let data: Vec<u8> = vec![0, 0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 1, 64, 3, 5];
 
         assert_eq!(sensor_info.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, sensor_info);
     }
     #[test]
     fn quat_fancy() {
@@ -97,7 +102,7 @@ mod tests {
     fn test_user_action() {
         let ua = PacketType::UserAction {
             packet_id: 1,
-            typ: 3,
+            typ: UserActionType::ResetYaw,
         };
         assert_eq!(
             ua.to_bytes().unwrap(),
@@ -109,4 +114,261 @@ mod tests {
         let hr = PacketType::HandshakeResponse;
         assert_eq!(hr.to_bytes().unwrap(), "\x03Hey".as_bytes());
     }
+    #[test]
+    fn test_battery_level() {
+        let battery = PacketType::BatteryLevel {
+            packet_id: 1,
+            voltage: 3.7,
+            level: 0.5,
+        };
+        let data: Vec<u8> = vec![
+            0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 1, 64, 108, 204, 205, 63, 0, 0, 0,
+        ];
+
+        assert_eq!(battery.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, battery);
+    }
+    #[test]
+    fn test_tap() {
+        let tap = PacketType::Tap {
+            packet_id: 1,
+            sensor_id: 2,
+            value: 3,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3];
+
+        assert_eq!(tap.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, tap);
+    }
+    #[test]
+    fn test_error() {
+        let error = PacketType::Error {
+            packet_id: 1,
+            sensor_id: 0,
+            error: 5,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0, 1, 0, 5];
+
+        assert_eq!(error.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, error);
+    }
+    #[test]
+    fn test_magnetometer_accuracy() {
+        let accuracy = PacketType::MagnetometerAccuracy {
+            packet_id: 1,
+            sensor_id: 1,
+            accuracy: 2.0,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 18, 0, 0, 0, 0, 0, 0, 0, 1, 1, 64, 0, 0, 0];
+
+        assert_eq!(accuracy.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, accuracy);
+    }
+    #[test]
+    fn test_signal_strength() {
+        let signal = PacketType::SignalStrength {
+            packet_id: 1,
+            sensor_id: 255,
+            strength: -60,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 19, 0, 0, 0, 0, 0, 0, 0, 1, 255, 196];
+
+        assert_eq!(signal.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, signal);
+    }
+    #[test]
+    fn test_temperature() {
+        let temperature = PacketType::Temperature {
+            packet_id: 1,
+            sensor_id: 0,
+            temperature: 25.0,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 1, 0, 65, 200, 0, 0];
+
+        assert_eq!(temperature.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, temperature);
+    }
+    #[test]
+    fn test_feature_flags() {
+        let flags = FeatureFlags::default().with(0).with(9);
+        assert!(flags.has(0));
+        assert!(flags.has(9));
+        assert!(!flags.has(1));
+        assert!(!flags.has(200));
+
+        let packet = PacketType::FeatureFlags {
+            packet_id: 1,
+            flags,
+        };
+        let data: Vec<u8> = vec![0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2];
+
+        assert_eq!(packet.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, packet);
+
+        // Without any flags the packet ends after the packet number
+        let empty = PacketType::FeatureFlags {
+            packet_id: 1,
+            flags: FeatureFlags::default(),
+        };
+        assert_eq!(empty.to_bytes().unwrap(), &data[..12]);
+        assert_eq!(PacketType::from_bytes((&data[..12], 0)).unwrap().1, empty);
+    }
+    #[test]
+    fn test_bundle() {
+        let bundle = PacketType::Bundle {
+            packet_id: 1,
+            packets: vec![
+                BundledPacket::from(vec![0, 0, 0, 13, 2, 3]),
+                BundledPacket::from(vec![0, 0, 0, 14, 0, 5]),
+            ],
+        };
+        let data: Vec<u8> = vec![
+            0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0, 1, 0, 6, 0, 0, 0, 13, 2, 3, 0, 6, 0, 0, 0, 14, 0, 5,
+        ];
+
+        assert_eq!(bundle.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, bundle);
+
+        // Receive buffers are zero padded
+        let mut padded = [0u8; 64];
+        padded[..data.len()].copy_from_slice(&data);
+        assert_eq!(PacketType::from_bytes((&padded, 0)).unwrap().1, bundle);
+    }
+    #[test]
+    fn test_server_heartbeat() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 1]).unwrap(),
+            ServerPacket::Heartbeat
+        );
+    }
+    #[test]
+    fn test_server_vibrate() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 2]).unwrap(),
+            ServerPacket::Vibrate
+        );
+    }
+    #[test]
+    fn test_server_command() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 4, 3]).unwrap(),
+            ServerPacket::Command { command: 3 }
+        );
+    }
+    #[test]
+    fn test_server_ping() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 10, 1, 2, 3, 4]).unwrap(),
+            ServerPacket::Ping { id: 16909060 }
+        );
+    }
+    #[test]
+    fn test_server_sensor_info() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 15, 64, 1]).unwrap(),
+            ServerPacket::SensorInfo {
+                sensor_id: 64,
+                sensor_status: SensorStatus::Ok,
+            }
+        );
+    }
+    #[test]
+    fn test_server_feature_flags() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 3]).unwrap(),
+            ServerPacket::FeatureFlags {
+                packet_id: 1,
+                flags: FeatureFlags::default().with(0).with(1),
+            }
+        );
+    }
+    #[test]
+    fn test_server_feature_flags_length() {
+        // The server only sends as many bytes as it knows flags for, currently one
+        let Ok(ServerPacket::FeatureFlags { flags, .. }) =
+            ServerPacket::decode(&[0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 1])
+        else {
+            panic!("Feature flags with a single byte did not decode");
+        };
+        assert!(flags.has(FeatureFlags::SERVER_BUNDLE));
+        assert!(!flags.has(1));
+        assert!(!flags.has(8));
+        assert!(!flags.has(31));
+
+        // Longer or zero padded flags decode as well
+        let Ok(ServerPacket::FeatureFlags { flags, .. }) =
+            ServerPacket::decode(&[0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0])
+        else {
+            panic!("Feature flags with padding did not decode");
+        };
+        assert!(flags.has(FeatureFlags::SERVER_BUNDLE));
+        assert!(!flags.has(40));
+    }
+    #[test]
+    fn test_server_handshake_response() {
+        let mut data = [0u8; 32];
+        data[..13].copy_from_slice("\x03Hey OVR =D 5".as_bytes());
+        assert_eq!(
+            ServerPacket::decode(&data).unwrap(),
+            ServerPacket::HandshakeResponse
+        );
+    }
+    #[test]
+    fn test_server_unknown() {
+        assert_eq!(
+            ServerPacket::decode(&[0, 0, 0, 99, 1, 2]),
+            Err(DecodeError::UnknownId(99))
+        );
+        assert!(matches!(
+            ServerPacket::decode(&[0, 0]),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+    #[test]
+    fn test_to_bundled() {
+        let tap = PacketType::Tap {
+            packet_id: 1,
+            sensor_id: 2,
+            value: 3,
+        };
+        assert_eq!(
+            tap.to_bundled().unwrap(),
+            BundledPacket::from(vec![0, 0, 0, 13, 2, 3])
+        );
+        assert_eq!(tap.to_bundled().unwrap().size(), 8);
+
+        let ping = PacketType::Ping { id: 16909060 };
+        assert_eq!(
+            ping.to_bundled().unwrap(),
+            BundledPacket::from(vec![0, 0, 0, 10, 1, 2, 3, 4])
+        );
+    }
+    #[test]
+    fn test_unknown_types() {
+        let handshake = PacketType::Handshake {
+            packet_id: 1,
+            board: BoardType::Unknown(0),
+            imu: ImuType::Unknown(-1),
+            mcu_type: McuType::Unknown(1000),
+            imu_info: (0, 0, 0),
+            build: 9,
+            firmware: "".to_string().into(),
+            mac_address: [0; 6],
+        };
+        let data: Vec<u8> = vec![
+            0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 3, 232, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        assert_eq!(handshake.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, handshake);
+
+        let ua = PacketType::UserAction {
+            packet_id: 1,
+            typ: UserActionType::Unknown(42),
+        };
+        let data = [0, 0, 0, 21, 0, 0, 0, 0, 0, 0, 0, 1, 42];
+        assert_eq!(ua.to_bytes().unwrap(), data);
+        assert_eq!(PacketType::from_bytes((&data, 0)).unwrap().1, ua);
+    }
 }
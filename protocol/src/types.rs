@@ -0,0 +1,141 @@
+use deku::prelude::*;
+
+// Values from the SlimeVR firmware and server. Anything not listed here, including 0 for
+// "unknown", ends up in the `Unknown` variant with its raw value.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "i32", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum BoardType {
+    #[deku(id = "1")]
+    SlimevrLegacy,
+    #[deku(id = "2")]
+    SlimevrDev,
+    #[deku(id = "3")]
+    NodeMcu,
+    #[deku(id = "4")]
+    Custom,
+    #[deku(id = "5")]
+    Wroom32,
+    #[deku(id = "6")]
+    WemosD1Mini,
+    #[deku(id = "7")]
+    TtgoTbase,
+    #[deku(id = "8")]
+    Esp01,
+    #[deku(id = "9")]
+    Slimevr,
+    #[deku(id = "10")]
+    LolinC3Mini,
+    #[deku(id = "11")]
+    Beetle32C3,
+    #[deku(id = "12")]
+    Esp32C3DevKitM1,
+    #[deku(id = "13")]
+    OwoTrack,
+    #[deku(id = "14")]
+    Wrangler,
+    #[deku(id_pat = "_")]
+    Unknown(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "i32", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum ImuType {
+    #[deku(id = "1")]
+    Mpu9250,
+    #[deku(id = "2")]
+    Mpu6500,
+    #[deku(id = "3")]
+    Bno080,
+    #[deku(id = "4")]
+    Bno085,
+    #[deku(id = "5")]
+    Bno055,
+    #[deku(id = "6")]
+    Mpu6050,
+    #[deku(id = "7")]
+    Bno086,
+    #[deku(id = "8")]
+    Bmi160,
+    #[deku(id = "9")]
+    Icm20948,
+    #[deku(id = "10")]
+    Icm42688,
+    #[deku(id_pat = "_")]
+    Unknown(i32),
+}
+
+/// Same values as [`ImuType`], but sent as a single byte in sensor info packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum SensorType {
+    #[deku(id = "1")]
+    Mpu9250,
+    #[deku(id = "2")]
+    Mpu6500,
+    #[deku(id = "3")]
+    Bno080,
+    #[deku(id = "4")]
+    Bno085,
+    #[deku(id = "5")]
+    Bno055,
+    #[deku(id = "6")]
+    Mpu6050,
+    #[deku(id = "7")]
+    Bno086,
+    #[deku(id = "8")]
+    Bmi160,
+    #[deku(id = "9")]
+    Icm20948,
+    #[deku(id = "10")]
+    Icm42688,
+    #[deku(id_pat = "_")]
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "i32", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum McuType {
+    #[deku(id = "1")]
+    Esp8266,
+    #[deku(id = "2")]
+    Esp32,
+    #[deku(id = "3")]
+    OwoTrackAndroid,
+    #[deku(id = "4")]
+    Wrangler,
+    #[deku(id = "5")]
+    OwoTrackIos,
+    #[deku(id = "6")]
+    Esp32C3,
+    #[deku(id_pat = "_")]
+    Unknown(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum SensorStatus {
+    #[deku(id = "0")]
+    Disconnected,
+    #[deku(id = "1")]
+    Ok,
+    #[deku(id = "2")]
+    Error,
+    #[deku(id_pat = "_")]
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(type = "u8", endian = "endian", ctx = "endian: deku::ctx::Endian")]
+pub enum UserActionType {
+    #[deku(id = "2")]
+    ResetFull,
+    #[deku(id = "3")]
+    ResetYaw,
+    #[deku(id = "4")]
+    ResetMounting,
+    #[deku(id = "5")]
+    PauseTracking,
+    #[deku(id_pat = "_")]
+    Unknown(u8),
+}
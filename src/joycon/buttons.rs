@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use crate::settings::{Action, Binding, Button};
+
+const LONG_PRESS: Duration = Duration::from_millis(1000);
+
+/// Turns the held buttons of one controller into actions, the same way for every integration.
+///
+/// A combo is every button held since the first one went down. Short presses fire when all
+/// buttons are released, long presses fire once the combo has been held for a second.
+#[derive(Debug, Default)]
+pub struct ButtonMapper {
+    held: BTreeSet<Button>,
+    combo: BTreeSet<Button>,
+    pressed_at: Option<Instant>,
+    long_fired: bool,
+}
+
+impl ButtonMapper {
+    pub fn update(
+        &mut self,
+        held: BTreeSet<Button>,
+        now: Instant,
+        bindings: &[Binding],
+    ) -> Option<Action> {
+        if held.is_empty() {
+            self.held = held;
+            let combo = std::mem::take(&mut self.combo);
+            let long_fired = self.long_fired;
+            self.pressed_at = None;
+            self.long_fired = false;
+            if long_fired {
+                return None;
+            }
+            return find(bindings, &combo, false);
+        }
+        if self.held.is_empty() {
+            self.pressed_at = Some(now);
+        }
+        self.combo.extend(held.iter().copied());
+        self.held = held;
+        self.tick(now, bindings)
+    }
+
+    /// Checks for long presses while the buttons stay down.
+    pub fn tick(&mut self, now: Instant, bindings: &[Binding]) -> Option<Action> {
+        let pressed_at = self.pressed_at?;
+        if self.long_fired || now.duration_since(pressed_at) < LONG_PRESS {
+            return None;
+        }
+        let action = find(bindings, &self.combo, true);
+        self.long_fired = action.is_some();
+        action
+    }
+}
+
+fn find(bindings: &[Binding], combo: &BTreeSet<Button>, long_press: bool) -> Option<Action> {
+    if combo.is_empty() {
+        return None;
+    }
+    bindings
+        .iter()
+        .find(|b| b.long_press == long_press && &b.buttons == combo)
+        .map(|b| b.action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ResetKind;
+
+    const SHORT: Action = Action::Reset(ResetKind::Yaw);
+    const LONG: Action = Action::Reset(ResetKind::Full);
+    const COMBO: Action = Action::PauseTracker;
+
+    fn binding(buttons: &[Button], long_press: bool, action: Action) -> Binding {
+        Binding {
+            buttons: held(buttons),
+            long_press,
+            action,
+        }
+    }
+
+    fn bindings() -> Vec<Binding> {
+        vec![
+            binding(&[Button::A], false, SHORT),
+            binding(&[Button::A], true, LONG),
+            binding(&[Button::A, Button::B], false, COMBO),
+        ]
+    }
+
+    fn held(buttons: &[Button]) -> BTreeSet<Button> {
+        buttons.iter().copied().collect()
+    }
+
+    #[test]
+    fn short_press_fires_on_release() {
+        let bindings = bindings();
+        let mut mapper = ButtonMapper::default();
+        let start = Instant::now();
+
+        assert_eq!(mapper.update(held(&[Button::A]), start, &bindings), None);
+        assert_eq!(
+            mapper.tick(start + Duration::from_millis(500), &bindings),
+            None
+        );
+        assert_eq!(
+            mapper.update(held(&[]), start + Duration::from_millis(600), &bindings),
+            Some(SHORT)
+        );
+    }
+
+    #[test]
+    fn long_press_fires_once_while_held() {
+        let bindings = bindings();
+        let mut mapper = ButtonMapper::default();
+        let start = Instant::now();
+
+        mapper.update(held(&[Button::A]), start, &bindings);
+        assert_eq!(mapper.tick(start + LONG_PRESS, &bindings), Some(LONG));
+        assert_eq!(
+            mapper.tick(start + LONG_PRESS * 2, &bindings),
+            None,
+            "Long press only fires once"
+        );
+        assert_eq!(
+            mapper.update(held(&[]), start + LONG_PRESS * 3, &bindings),
+            None,
+            "No short press after a long press"
+        );
+    }
+
+    #[test]
+    fn combo_of_two_buttons() {
+        let bindings = bindings();
+        let mut mapper = ButtonMapper::default();
+        let start = Instant::now();
+
+        mapper.update(held(&[Button::A]), start, &bindings);
+        mapper.update(
+            held(&[Button::A, Button::B]),
+            start + Duration::from_millis(100),
+            &bindings,
+        );
+        // The buttons don't have to be released together
+        assert_eq!(
+            mapper.update(
+                held(&[Button::B]),
+                start + Duration::from_millis(200),
+                &bindings
+            ),
+            None
+        );
+        assert_eq!(
+            mapper.update(held(&[]), start + Duration::from_millis(300), &bindings),
+            Some(COMBO)
+        );
+    }
+
+    #[test]
+    fn release_before_long_press() {
+        let bindings = [binding(&[Button::A], true, LONG)];
+        let mut mapper = ButtonMapper::default();
+        let start = Instant::now();
+
+        mapper.update(held(&[Button::A]), start, &bindings);
+        assert_eq!(
+            mapper.update(held(&[]), start + LONG_PRESS / 2, &bindings),
+            None
+        );
+        assert_eq!(mapper.tick(start + LONG_PRESS * 2, &bindings), None);
+
+        // The next press starts its own timer
+        let again = start + LONG_PRESS * 3;
+        mapper.update(held(&[Button::A]), again, &bindings);
+        assert_eq!(mapper.tick(again + LONG_PRESS / 2, &bindings), None);
+        assert_eq!(mapper.tick(again + LONG_PRESS, &bindings), Some(LONG));
+    }
+}
@@ -0,0 +1,13 @@
+use std::time::Instant;
+
+/// Time source of [`super::Communication`], so tests can move time forward without sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
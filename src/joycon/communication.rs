@@ -1,21 +1,29 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fmt::Display,
-    net::{SocketAddr, UdpSocket},
+    io,
+    net::SocketAddr,
     sync::mpsc,
     time::{Duration, Instant},
 };
 
 use itertools::Itertools;
 use nalgebra::{UnitQuaternion, Vector3};
-use protocol::deku::{DekuContainerRead, DekuContainerWrite};
-use protocol::PacketType;
+use protocol::deku::DekuContainerWrite;
+use protocol::{
+    BoardType, BundledPacket, DecodeError, FeatureFlags, ImuType, McuType, PacketType,
+    SensorStatus, SensorType, ServerPacket, UserActionType,
+};
 
 use super::{
+    buttons::ButtonMapper,
+    clock::{Clock, SystemClock},
     imu::{Imu, JoyconAxisData},
+    recorder::Recorder,
+    transport::{Transport, UdpTransport},
     JoyconDesign,
 };
-use crate::settings;
+use crate::settings::{self, Action, Button, ResetKind};
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Battery {
@@ -25,6 +33,32 @@ pub enum Battery {
     Medium,
     Full,
 }
+impl Battery {
+    /// Rough charge of each level the controllers report, from 0.0 to 1.0.
+    pub fn level(self) -> f32 {
+        match self {
+            Battery::Empty => 0.0,
+            Battery::Critical => 0.1,
+            Battery::Low => 0.3,
+            Battery::Medium => 0.6,
+            Battery::Full => 1.0,
+        }
+    }
+    pub fn from_level(level: f32) -> Self {
+        match level {
+            x if x >= 0.8 => Battery::Full,
+            x if x >= 0.45 => Battery::Medium,
+            x if x >= 0.2 => Battery::Low,
+            x if x > 0.05 => Battery::Critical,
+            _ => Battery::Empty,
+        }
+    }
+}
+
+// Joy-Cons and Pro Controllers use a single li-ion cell, 3.3V empty and 4.2V full.
+fn battery_voltage(level: f32) -> f32 {
+    3.3 + 0.9 * level.clamp(0.0, 1.0)
+}
 
 #[derive(Debug, Clone)]
 pub struct Status {
@@ -33,6 +67,11 @@ pub struct Status {
     pub serial_number: String,
     pub battery: Battery,
     pub status: DeviceStatus,
+    pub packets_sent: u64,
+    pub packets_per_second: f32,
+    /// Standard deviation of the time between IMU reports, high values point at Bluetooth trouble.
+    pub imu_jitter: Duration,
+    pub paused: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -59,21 +98,58 @@ struct Device {
     design: JoyconDesign,
     send_id: u8,
     battery: Battery,
+    battery_level: f32,
     status: DeviceStatus,
     imu_times: Vec<Instant>,
+    imu_jitter: Duration,
+    packets_sent: u64,
+    /// Time and `packets_sent` of the last packets per second update.
+    rate_sample: (Instant, u64),
+    packets_per_second: f32,
+    buttons: ButtonMapper,
+    paused: bool,
+    connected_at: Instant,
+    /// Sensor status the servers were told last.
+    reported: SensorStatus,
 }
 
 impl Device {
-    pub fn handshake(&self, socket: &UdpSocket, address: &SocketAddr) {
+    /// Sent on handshake and whenever the sensor status changes.
+    fn send_sensor_info(&mut self, socket: &dyn Transport, server: &mut Server) {
         let sensor_info = PacketType::SensorInfo {
             packet_id: 0,
             sensor_id: self.send_id,
-            sensor_status: 1,
-            sensor_type: 0,
+            sensor_status: self.reported,
+            sensor_type: SensorType::Unknown(0),
         };
-        socket
-            .send_to(&sensor_info.to_bytes().unwrap(), address)
-            .unwrap();
+        server.send(socket, &sensor_info);
+        self.packets_sent += 1;
+    }
+
+    fn sensor_status(&self, now: Instant) -> SensorStatus {
+        match self.status {
+            DeviceStatus::Disconnected => SensorStatus::Disconnected,
+            // A controller that just connected gets a moment to start sending
+            DeviceStatus::NoIMU
+                if now.saturating_duration_since(self.connected_at) >= Duration::from_secs(1) =>
+            {
+                SensorStatus::Error
+            }
+            _ => SensorStatus::Ok,
+        }
+    }
+}
+
+/// Packet numbers of one connection, the server uses them to drop late and duplicate packets.
+#[derive(Debug, Default)]
+struct Sequence(u64);
+impl Sequence {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+    fn reset(&mut self) {
+        self.0 = 0;
     }
 }
 
@@ -91,12 +167,37 @@ impl ChannelData {
     }
 }
 
+/// Standard deviation of the time between reports.
+fn jitter(times: &[Instant]) -> Duration {
+    if times.len() < 3 {
+        return Duration::ZERO;
+    }
+    let intervals: Vec<f64> = times
+        .windows(2)
+        .map(|w| w[1].saturating_duration_since(w[0]).as_secs_f64())
+        .collect();
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    let variance =
+        intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+    Duration::from_secs_f64(variance.sqrt())
+}
+
+fn reset_action(kind: ResetKind) -> UserActionType {
+    match kind {
+        ResetKind::Yaw => UserActionType::ResetYaw,
+        ResetKind::Full => UserActionType::ResetFull,
+        ResetKind::Mounting => UserActionType::ResetMounting,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ChannelInfo {
     Connected(JoyconDesign),
     ImuData([JoyconAxisData; 3]),
-    Battery(Battery),
-    Reset,
+    /// Battery level, with the exact charge from 0.0 to 1.0 if the integration knows it.
+    Battery(Battery, Option<f32>),
+    /// Every button that is currently held down, sent whenever it changes.
+    Buttons(BTreeSet<Button>),
     Disconnected,
 }
 
@@ -139,119 +240,782 @@ pub enum ServerStatus {
     Disconnected,
     Unknown,
     Connected,
+    /// Sending to the server failed, it is retried with growing delays.
+    Error(io::ErrorKind),
+}
+
+impl Display for ServerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerStatus::Disconnected => f.write_str("Disconnected"),
+            ServerStatus::Unknown => f.write_str("Unknown"),
+            ServerStatus::Connected => f.write_str("Connected"),
+            ServerStatus::Error(kind) => write!(f, "Error ({kind})"),
+        }
+    }
+}
+
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// Controller of the emulated device, when every controller is its own device.
+    pub serial_number: Option<String>,
+    /// Address in the settings.
+    pub configured: SocketAddr,
+    /// Address the server answered from, differs from `configured` once it was discovered.
+    pub address: SocketAddr,
+    pub status: ServerStatus,
+    pub rtt: Option<Duration>,
+    pub send_errors: u64,
+}
+impl ServerInfo {
+    pub fn discovered(&self) -> bool {
+        self.address != self.configured
+    }
+}
+
+/// Connection to one SlimeVR server, every server gets the same tracker data.
+struct Server {
+    configured: SocketAddr,
+    address: SocketAddr,
+    sequence: Sequence,
+    features: Option<FeatureFlags>,
+    status: ServerStatus,
+    last_handshake: Instant,
+    last_ping: Instant,
+    /// The server answers every feature flags packet, which is used to measure the round trip.
+    /// Pings can't be used for that: the server times its own ping against our echo and keeps
+    /// the result, and it ignores pings that a tracker starts.
+    last_probe: Instant,
+    probe_sent: Option<Instant>,
+    rtt: Option<Duration>,
+    send_errors: u64,
+    /// Set by a failed send, turned into `ServerStatus::Error` at the end of the loop.
+    failure: Option<io::ErrorKind>,
+    retry_at: Instant,
+    retry_delay: Duration,
+}
+
+impl Server {
+    fn new(address: SocketAddr, now: Instant) -> Self {
+        Self {
+            configured: address,
+            address,
+            sequence: Sequence::default(),
+            features: None,
+            status: ServerStatus::Disconnected,
+            // Handshake right away
+            last_handshake: now.checked_sub(Duration::from_secs(60)).unwrap(),
+            last_ping: now,
+            last_probe: now,
+            probe_sent: None,
+            rtt: None,
+            send_errors: 0,
+            failure: None,
+            retry_at: now,
+            retry_delay: FIRST_RETRY,
+        }
+    }
+
+    fn info(&self, serial_number: Option<String>) -> ServerInfo {
+        ServerInfo {
+            serial_number,
+            configured: self.configured,
+            address: self.address,
+            status: self.status,
+            rtt: self.rtt,
+            send_errors: self.send_errors,
+        }
+    }
+
+    fn supports_bundles(&self) -> bool {
+        self.features
+            .as_ref()
+            .map_or(false, |f| f.has(FeatureFlags::SERVER_BUNDLE))
+    }
+
+    /// Sends a packet with the next packet number of this connection, which every packet apart
+    /// from ping and handshake response stores in bytes 4..12.
+    fn send(&mut self, socket: &dyn Transport, packet: &PacketType) {
+        let mut bytes = packet.to_bytes().unwrap();
+        bytes[4..12].copy_from_slice(&self.sequence.next().to_be_bytes());
+        self.send_bytes(socket, &bytes);
+    }
+
+    fn send_bytes(&mut self, socket: &dyn Transport, bytes: &[u8]) {
+        // Nothing goes out until the retry
+        if matches!(self.status, ServerStatus::Error(_)) || self.failure.is_some() {
+            return;
+        }
+        if let Err(e) = socket.send_to(bytes, self.address) {
+            self.send_errors += 1;
+            // A full send buffer or a signal only loses this packet
+            if !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ) {
+                self.failure = Some(e.kind());
+            }
+        }
+    }
+
+    fn disconnect(&mut self, status: ServerStatus) {
+        self.status = status;
+        self.features = None;
+        self.probe_sent = None;
+        self.rtt = None;
+    }
+
+    /// Always the same flags, so sending them again only makes the server store what it already
+    /// knows and answer with its own flags.
+    fn send_feature_flags(&mut self, socket: &dyn Transport, now: Instant) {
+        let flags = PacketType::FeatureFlags {
+            packet_id: 0,
+            flags: FeatureFlags::default(),
+        };
+        self.send(socket, &flags);
+        self.last_probe = now;
+        self.probe_sent = Some(now);
+    }
+
+    fn handshake(&mut self, socket: &dyn Transport, mac_address: [u8; 6], discover: bool) {
+        self.sequence.reset();
+        let handshake = PacketType::Handshake {
+            packet_id: self.sequence.next(),
+            board: BoardType::Unknown(0),
+            imu: ImuType::Unknown(0),
+            mcu_type: McuType::Unknown(0),
+            imu_info: (0, 0, 0),
+            build: 9,
+            firmware: "slimevr-wrangler".to_string().into(),
+            mac_address,
+        };
+        let handshake = handshake.to_bytes().unwrap();
+        self.send_bytes(socket, &handshake);
+        if discover && self.failure.is_none() {
+            // Servers on the local network answer from their own address, see `step`.
+            // IPv4 only, there is no broadcast on an IPv6 socket so this send just fails.
+            let broadcast = SocketAddr::from(([255, 255, 255, 255], self.address.port()));
+            socket.send_to(&handshake, broadcast).ok();
+        }
+    }
+}
+
+/// One emulated tracker device with its own socket, MAC address and connection to every server.
+struct Link {
+    socket: Box<dyn Transport>,
+    mac_address: [u8; 6],
+    /// The only controller of this device, or `None` when it carries every controller.
+    serial_number: Option<String>,
+    servers: Vec<Server>,
+    pending: Vec<PacketType>,
+}
+
+impl Link {
+    fn new(
+        socket: Box<dyn Transport>,
+        mac_address: [u8; 6],
+        serial_number: Option<String>,
+        addresses: &[SocketAddr],
+        now: Instant,
+    ) -> Self {
+        Self {
+            socket,
+            mac_address,
+            serial_number,
+            servers: addresses
+                .iter()
+                .map(|address| Server::new(*address, now))
+                .collect(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn carries(&self, serial_number: &str) -> bool {
+        self.serial_number
+            .as_deref()
+            .map_or(true, |sn| sn == serial_number)
+    }
+
+    /// Servers that stay in the list keep their connection, new ones handshake right away.
+    fn set_addresses(&mut self, addresses: &[SocketAddr], now: Instant) {
+        let mut old = std::mem::take(&mut self.servers);
+        self.servers = addresses
+            .iter()
+            .map(|&address| {
+                // A saved discovered address keeps its connection too
+                match old
+                    .iter()
+                    .position(|s| s.configured == address || s.address == address)
+                {
+                    Some(i) => {
+                        let mut server = old.remove(i);
+                        server.configured = address;
+                        server
+                    }
+                    None => Server::new(address, now),
+                }
+            })
+            .collect();
+    }
+
+    /// Sends the packet to every server, each with its own packet number.
+    fn send_all(&mut self, packet: &PacketType) {
+        for server in &mut self.servers {
+            server.send(&*self.socket, packet);
+        }
+    }
+
+    /// Sends the sensor packets queued up during this loop, in bundles to the servers that
+    /// support them.
+    fn flush_pending(&mut self, bundle_packets: bool) {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return;
+        }
+        let bundle = bundle_packets && pending.len() >= 2;
+        let bundles = if bundle && self.servers.iter().any(Server::supports_bundles) {
+            let bundled: Vec<_> = pending.iter().map(|p| p.to_bundled().unwrap()).collect();
+            split_bundles(&bundled)
+        } else {
+            Vec::new()
+        };
+
+        for server in &mut self.servers {
+            if bundle && server.supports_bundles() {
+                for packets in &bundles {
+                    let bundle = PacketType::Bundle {
+                        packet_id: 0,
+                        packets: packets.clone(),
+                    };
+                    server.send(&*self.socket, &bundle);
+                }
+            } else {
+                for packet in &pending {
+                    server.send(&*self.socket, packet);
+                }
+            }
+        }
+    }
+}
+
+// Bundle header is packet type and packet number. Stay well below the server receive buffer.
+const BUNDLE_HEADER_SIZE: usize = 12;
+const MAX_BUNDLE_SIZE: usize = 500;
+
+fn split_bundles(packets: &[BundledPacket]) -> Vec<Vec<BundledPacket>> {
+    let mut bundles = Vec::new();
+    let mut bundle = Vec::new();
+    let mut size = BUNDLE_HEADER_SIZE;
+    for packet in packets {
+        if !bundle.is_empty() && size + packet.size() > MAX_BUNDLE_SIZE {
+            bundles.push(std::mem::take(&mut bundle));
+            size = BUNDLE_HEADER_SIZE;
+        }
+        size += packet.size();
+        bundle.push(packet.clone());
+    }
+    bundles.push(bundle);
+    bundles
 }
 
 pub struct Communication {
     receive: mpsc::Receiver<ChannelData>,
     status_tx: mpsc::Sender<Vec<Status>>,
-    server_tx: mpsc::Sender<ServerStatus>,
+    server_tx: mpsc::Sender<Vec<ServerInfo>>,
     settings: settings::Handler,
 
     devices: HashMap<String, Device>,
 
     use_keep_ids: bool,
-    socket: UdpSocket,
-    address: SocketAddr,
-    connected: ServerStatus,
-    last_handshake: Instant,
-    last_ping: Instant,
+    device_per_controller: bool,
+    clock: Box<dyn Clock>,
+    /// In the shared mode the only link, which carries every controller. Otherwise there is one
+    /// link per controller.
+    links: Vec<Link>,
+    /// The socket from `new` until the first controller takes it, in the device per controller mode.
+    spare_socket: Option<Box<dyn Transport>>,
+    /// Server addresses every link connects to.
+    addresses: Vec<SocketAddr>,
+    /// What the UI was sent last, to only send changes.
+    sent_servers: Vec<ServerInfo>,
+    /// Packets received from addresses that are not one of the servers.
+    ignored: HashMap<SocketAddr, u64>,
+    /// Packet ids the servers sent that could not be decoded, they are only logged once.
+    unknown_ids: HashSet<u32>,
+    /// Records the controller data while the settings ask for it.
+    recorder: Option<Recorder>,
     last_reset: Instant,
+    last_battery: Instant,
+    last_ui_send: Instant,
 }
 impl Communication {
     pub fn start(
         receive: mpsc::Receiver<ChannelData>,
         status_tx: mpsc::Sender<Vec<Status>>,
-        server_tx: mpsc::Sender<ServerStatus>,
+        server_tx: mpsc::Sender<Vec<ServerInfo>>,
         settings: settings::Handler,
     ) {
-        let addrs = [
-            SocketAddr::from(([0, 0, 0, 0], 47589)),
-            SocketAddr::from(([0, 0, 0, 0], 0)),
-        ];
-        let socket = UdpSocket::bind(&addrs[..]).unwrap();
-        socket.set_nonblocking(true).ok();
-        let address = { settings.load().get_socket_address() };
-        let use_keep_ids = { settings.load().keep_ids };
+        let bind_address = { settings.load().get_bind_address() };
+        let socket = UdpTransport::bind(bind_address)
+            .or_else(|e| {
+                println!(
+                    "[WARNING] Server - Could not bind to {bind_address} ({e}), using {}.",
+                    settings::DEFAULT_BIND_ADDR
+                );
+                UdpTransport::bind(settings::DEFAULT_BIND_ADDR.parse().unwrap())
+            })
+            .unwrap();
 
-        server_tx.send(ServerStatus::Disconnected).ok();
+        Self::new(
+            receive,
+            status_tx,
+            server_tx,
+            settings,
+            Box::new(SystemClock),
+            Box::new(socket),
+        )
+        .main_loop();
+    }
 
-        Self {
+    pub fn new(
+        receive: mpsc::Receiver<ChannelData>,
+        status_tx: mpsc::Sender<Vec<Status>>,
+        server_tx: mpsc::Sender<Vec<ServerInfo>>,
+        settings: settings::Handler,
+        clock: Box<dyn Clock>,
+        socket: Box<dyn Transport>,
+    ) -> Self {
+        let mut addresses = { settings.load().get_socket_addresses() };
+        if addresses.is_empty() {
+            addresses.push(settings::DEFAULT_ADDR.parse().unwrap());
+        }
+        let (use_keep_ids, device_per_controller, mac_address) = {
+            let settings = settings.load();
+            (
+                settings.keep_ids,
+                settings.device_per_controller,
+                settings.emulated_mac,
+            )
+        };
+        let now = clock.now();
+        let (links, spare_socket) = if device_per_controller {
+            (Vec::new(), Some(socket))
+        } else {
+            let link = Link::new(socket, mac_address, None, &addresses, now);
+            (vec![link], None)
+        };
+
+        let mut communication = Self {
             receive,
             status_tx,
             server_tx,
             settings,
             devices: HashMap::new(),
             use_keep_ids,
+            device_per_controller,
+            clock,
+            links,
+            spare_socket,
+            addresses,
+            sent_servers: Vec::new(),
+            ignored: HashMap::new(),
+            unknown_ids: HashSet::new(),
+            recorder: None,
+            last_reset: now,
+            last_battery: now,
+            last_ui_send: now,
+        };
+        communication.send_servers();
+        communication
+    }
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(since)
+    }
+
+    fn send_servers(&mut self) {
+        let servers: Vec<_> = self
+            .links
+            .iter()
+            .flat_map(|link| {
+                link.servers
+                    .iter()
+                    .map(|server| server.info(link.serial_number.clone()))
+            })
+            .collect();
+        if servers != self.sent_servers {
+            self.server_tx.send(servers.clone()).ok();
+            self.sent_servers = servers;
+        }
+    }
+
+    /// Follows changes to the server list in the settings, devices keep their sensor ids.
+    fn check_addresses(&mut self) {
+        let addresses = self.settings.load().get_socket_addresses();
+        if addresses.is_empty() || addresses == self.addresses {
+            return;
+        }
+        let now = self.clock.now();
+        for link in &mut self.links {
+            link.set_addresses(&addresses, now);
+        }
+        self.addresses = addresses;
+        self.send_servers();
+    }
+
+    /// Follows the keep ids setting and changes to the saved ids, like swapped or compacted ids.
+    /// The servers are told about the new sensor ids and the ones that are not used anymore.
+    fn check_sensor_ids(&mut self) {
+        if self.device_per_controller {
+            return;
+        }
+        let use_keep_ids = self.settings.load().keep_ids;
+        let ids: Vec<(String, u8)> = if use_keep_ids {
+            let serial_numbers: Vec<_> = self
+                .devices
+                .iter()
+                .sorted_by_key(|(_, d)| d.send_id)
+                .map(|(sn, _)| sn.clone())
+                .collect();
+            serial_numbers
+                .into_iter()
+                .map(|sn| {
+                    // A forgotten id is not handed right back to the controller using it
+                    let freed = self.use_keep_ids.then(|| self.devices[&sn].send_id);
+                    let id = self.settings.joycon_keep_id(sn.clone(), freed);
+                    (sn, id)
+                })
+                .collect()
+        } else if self.use_keep_ids {
+            // Back to numbering in order
+            self.devices
+                .iter()
+                .sorted_by_key(|(_, d)| d.send_id)
+                .enumerate()
+                .map(|(i, (sn, _))| (sn.clone(), i as u8))
+                .collect()
+        } else {
+            return;
+        };
+        self.use_keep_ids = use_keep_ids;
+
+        let old: HashSet<u8> = self.devices.values().map(|d| d.send_id).collect();
+        let mut changed = false;
+        for (sn, id) in ids {
+            let device = self.devices.get_mut(&sn).unwrap();
+            changed |= device.send_id != id;
+            device.send_id = id;
+        }
+        if !changed {
+            return;
+        }
+        let new: HashSet<u8> = self.devices.values().map(|d| d.send_id).collect();
+        for &sensor_id in old.difference(&new) {
+            let sensor_info = PacketType::SensorInfo {
+                packet_id: 0,
+                sensor_id,
+                sensor_status: SensorStatus::Disconnected,
+                sensor_type: SensorType::Unknown(0),
+            };
+            self.links[0].send_all(&sensor_info);
+        }
+        for index in 0..self.links[0].servers.len() {
+            self.handshake_devices(0, index);
+        }
+    }
+
+    /// Index of the link that carries the controller.
+    fn link_of(&self, serial_number: &str) -> Option<usize> {
+        self.links.iter().position(|l| l.carries(serial_number))
+    }
+
+    /// Gives the controller its own device. The first one takes the socket from `new`, the
+    /// others open a new one.
+    fn add_link(&mut self, serial_number: &str) {
+        let socket = match self.spare_socket.take() {
+            Some(socket) => Ok(socket),
+            None => self.links[0].socket.open(),
+        };
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("[WARNING] Server - Could not open a socket for {serial_number}: {e}.");
+                return;
+            }
+        };
+        let mac_address = self.settings.joycon_mac(serial_number.to_string());
+        let now = self.clock.now();
+        self.links.push(Link::new(
             socket,
-            address,
-            connected: ServerStatus::Disconnected,
-            last_handshake: Instant::now().checked_sub(Duration::from_secs(60)).unwrap(),
-            last_ping: Instant::now(),
-            last_reset: Instant::now(),
+            mac_address,
+            Some(serial_number.to_string()),
+            &self.addresses,
+            now,
+        ));
+        self.send_servers();
+    }
+
+    /// Starts and stops recording when the settings change.
+    fn check_recording(&mut self) {
+        let wanted = self.settings.load().recording.clone();
+        if wanted.as_deref() == self.recorder.as_ref().map(Recorder::path) {
+            return;
+        }
+        if let Some(recorder) = self.recorder.take() {
+            let path = recorder.path().display().to_string();
+            if let Err(e) = recorder.finish() {
+                println!("[WARNING] Recorder - Could not finish {path}: {e}.");
+            }
+        }
+        let Some(path) = wanted else {
+            return;
+        };
+        match Recorder::create(&path, self.clock.now()) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => {
+                println!(
+                    "[WARNING] Recorder - Could not record to {}: {e}.",
+                    path.display()
+                );
+                self.settings.change(|ws| ws.recording = None);
+                return;
+            }
+        }
+        // Controllers that are already connected, so the recording stands on its own
+        let connected: Vec<_> = self
+            .devices
+            .iter()
+            .map(|(sn, device)| {
+                ChannelData::new(sn.clone(), ChannelInfo::Connected(device.design.clone()))
+            })
+            .collect();
+        for data in &connected {
+            self.record(data);
         }
-        .main_loop();
     }
 
-    fn send_handshake(&self) {
-        let handshake = PacketType::Handshake {
+    fn record(&mut self, data: &ChannelData) {
+        let now = self.clock.now();
+        if let Some(Err(e)) = self.recorder.as_mut().map(|r| r.write(data, now)) {
+            self.stop_recording(&e);
+        }
+    }
+
+    /// Once per loop, so a crash loses at most the records of one loop.
+    fn flush_recording(&mut self) {
+        if let Some(Err(e)) = self.recorder.as_mut().map(Recorder::flush) {
+            self.stop_recording(&e);
+        }
+    }
+
+    fn stop_recording(&mut self, error: &io::Error) {
+        if let Some(recorder) = self.recorder.take() {
+            println!(
+                "[WARNING] Recorder - Could not write to {}, stopping: {error}.",
+                recorder.path().display()
+            );
+        }
+        self.settings.change(|ws| ws.recording = None);
+    }
+
+    /// Turns failed sends into an error status, and retries the servers whose delay is over.
+    fn check_send_failures(&mut self) {
+        let now = self.clock.now();
+        for server in self.links.iter_mut().flat_map(|l| &mut l.servers) {
+            if let Some(kind) = server.failure.take() {
+                println!(
+                    "[WARNING] Server - Could not send to {}: {kind}.",
+                    server.address
+                );
+                server.disconnect(ServerStatus::Error(kind));
+                server.retry_at = now + server.retry_delay;
+                server.retry_delay = (server.retry_delay * 2).min(MAX_RETRY);
+            } else if matches!(server.status, ServerStatus::Error(_)) && now >= server.retry_at {
+                // Handshake again, which fails right away if the network is still down
+                server.status = ServerStatus::Disconnected;
+                server.last_handshake = now.checked_sub(Duration::from_secs(60)).unwrap();
+            }
+        }
+        self.send_servers();
+    }
+
+    fn handshake_devices(&mut self, link: usize, index: usize) {
+        let link = &mut self.links[link];
+        let server = &mut link.servers[index];
+        for device in self
+            .devices
+            .iter_mut()
+            .filter(|(sn, _)| link.serial_number.as_ref().map_or(true, |s| s == *sn))
+            .map(|(_, device)| device)
+            .sorted_by_key(|d| d.send_id)
+        {
+            device.send_sensor_info(&*link.socket, server);
+        }
+    }
+
+    /// Resets go out from the device of the controller, the server applies them to every tracker.
+    fn send_reset(&mut self, sn: &str, kind: ResetKind) {
+        let Some(link) = self.link_of(sn) else {
+            return;
+        };
+        let reset = PacketType::UserAction {
             packet_id: 0,
-            board: 0,
-            imu: 0,
-            mcu_type: 0,
-            imu_info: (0, 0, 0),
-            build: 9,
-            firmware: "slimevr-wrangler".to_string().into(),
-            mac_address: self.settings.load().emulated_mac,
+            typ: reset_action(kind),
         };
-        self.socket
-            .send_to(&handshake.to_bytes().unwrap(), self.address)
-            .unwrap();
+        self.links[link].send_all(&reset);
     }
 
-    fn send_reset(&self) {
-        let handshake = PacketType::UserAction {
+    fn send_battery(&mut self, link: usize) {
+        // In the shared mode all controllers are sensors of one tracker on the server, so report
+        // the emptiest one.
+        let link = &mut self.links[link];
+        let Some(level) = self
+            .devices
+            .iter()
+            .filter(|(sn, d)| link.carries(sn) && d.status != DeviceStatus::Disconnected)
+            .map(|(_, d)| d.battery_level)
+            .min_by(|a, b| a.total_cmp(b))
+        else {
+            return;
+        };
+        let battery = PacketType::BatteryLevel {
             packet_id: 0,
-            typ: 3,
+            voltage: battery_voltage(level),
+            level,
         };
-        self.socket
-            .send_to(&handshake.to_bytes().unwrap(), self.address)
-            .unwrap();
+        link.send_all(&battery);
+    }
+
+    fn flush_pending(&mut self) {
+        let bundle_packets = self.settings.load().bundle_packets;
+        for link in &mut self.links {
+            link.flush_pending(bundle_packets);
+        }
+    }
+
+    fn run_action(&mut self, sn: &str, action: Action) {
+        match action {
+            Action::Reset(kind) => {
+                let enabled = {
+                    let settings = self.settings.load();
+                    match kind {
+                        ResetKind::Yaw => settings.send_reset,
+                        ResetKind::Full => settings.send_full_reset,
+                        ResetKind::Mounting => settings.send_mounting_reset,
+                    }
+                };
+                if enabled && self.elapsed(self.last_reset).as_secs() >= 2 {
+                    self.last_reset = self.clock.now();
+                    self.send_reset(sn, kind);
+                }
+            }
+            Action::PauseTracker => {
+                if let Some(device) = self.devices.get_mut(sn) {
+                    device.paused = !device.paused;
+                }
+            }
+            Action::ReZero => {
+                let (bias, filter, vqf) = {
+                    let settings = self.settings.load();
+                    (
+                        settings.joycon_gyro_bias_get(sn),
+                        settings.joycon_filter_get(sn),
+                        settings.joycon_vqf_get(sn),
+                    )
+                };
+                if let Some(device) = self.devices.get_mut(sn) {
+                    device.imu = Imu::new(bias, filter, vqf);
+                }
+            }
+        }
     }
 
     fn parse_message(&mut self, msg: ChannelData) {
+        let now = self.clock.now();
         let sn = msg.serial_number;
         match msg.info {
             ChannelInfo::Connected(design) => {
+                self.settings
+                    .change_unsaved(|ws| ws.joycon_last_seen_set(sn.clone()));
+                // The saved bias spares the filter from learning it again
+                let (bias, filter, vqf) = {
+                    let settings = self.settings.load();
+                    (
+                        settings.joycon_gyro_bias_get(&sn),
+                        settings.joycon_filter_get(&sn),
+                        settings.joycon_vqf_get(&sn),
+                    )
+                };
                 if self.devices.contains_key(&sn) {
                     let device = self.devices.get_mut(&sn).unwrap();
-                    device.imu = Imu::new();
+                    device.imu = Imu::new(bias, filter, vqf);
                     device.imu_times = vec![];
+                    device.connected_at = now;
                     return;
                 }
 
-                let send_id = if self.use_keep_ids {
-                    self.settings.joycon_keep_id(sn.clone())
+                let send_id = if self.device_per_controller {
+                    0
+                } else if self.use_keep_ids {
+                    self.settings.joycon_keep_id(sn.clone(), None)
                 } else {
                     self.devices.len() as _
                 };
-                let device = Device {
-                    imu: Imu::new(),
+                let mut device = Device {
+                    imu: Imu::new(bias, filter, vqf),
                     design,
                     send_id,
                     battery: Battery::Full,
+                    battery_level: Battery::Full.level(),
                     status: DeviceStatus::NoIMU,
                     imu_times: vec![],
+                    imu_jitter: Duration::ZERO,
+                    packets_sent: 0,
+                    rate_sample: (now, 0),
+                    packets_per_second: 0.0,
+                    buttons: ButtonMapper::default(),
+                    paused: false,
+                    connected_at: now,
+                    reported: SensorStatus::Ok,
                 };
 
-                device.handshake(&self.socket, &self.address);
+                if self.device_per_controller {
+                    // The new device handshakes with its controller on the next loop
+                    self.add_link(&sn);
+                } else {
+                    let link = &mut self.links[0];
+                    for server in &mut link.servers {
+                        device.send_sensor_info(&*link.socket, server);
+                    }
+                }
                 self.devices.insert(sn, device);
             }
             ChannelInfo::ImuData(imu_data) => {
+                let link = self.link_of(&sn);
                 if let Some(device) = self.devices.get_mut(&sn) {
+                    let (filter, vqf) = {
+                        let settings = self.settings.load();
+                        (
+                            settings.joycon_filter_get(&sn),
+                            settings.joycon_vqf_get(&sn),
+                        )
+                    };
+                    device.imu.set_filter(filter, vqf);
                     for frame in imu_data {
-                        device.imu.update(frame);
+                        if let Some(bias) = device.imu.update(frame) {
+                            self.settings.change_unsaved(|ws| {
+                                ws.joycon_gyro_bias_set(sn.clone(), bias);
+                            });
+                        }
                     }
-                    device.imu_times.push(Instant::now());
+                    device.imu_times.push(now);
+                    let Some(link) = link.filter(|_| !device.paused) else {
+                        return;
+                    };
+                    let pending = &mut self.links[link].pending;
 
                     let joycon_rotation = self.settings.load().joycon_rotation_get(&sn);
                     let rad_rotation = (joycon_rotation as f64).to_radians();
@@ -262,40 +1026,47 @@ impl Communication {
                         device.imu.rotation
                     };
 
-                    let rotation_packet = PacketType::RotationData {
+                    pending.push(PacketType::RotationData {
                         packet_id: 0,
                         sensor_id: device.send_id,
                         data_type: 1,
                         quat: (*rotated_quat).into(),
                         calibration_info: 0,
-                    };
-                    self.socket
-                        .send_to(&rotation_packet.to_bytes().unwrap(), self.address)
-                        .unwrap();
+                    });
 
                     let acc = calc_acceleration(device.imu.rotation, &imu_data[2], rad_rotation);
-                    let acceleration_packet = PacketType::Acceleration {
+                    pending.push(PacketType::Acceleration {
                         packet_id: 0,
                         vector: (acc.x as f32, acc.y as f32, acc.z as f32),
                         sensor_id: Some(device.send_id),
-                    };
-                    self.socket
-                        .send_to(&acceleration_packet.to_bytes().unwrap(), self.address)
-                        .unwrap();
+                    });
+                    device.packets_sent += 2;
                 }
             }
-            ChannelInfo::Battery(battery) => {
+            ChannelInfo::Battery(battery, level) => {
                 if let Some(device) = self.devices.get_mut(&sn) {
+                    let level = level.unwrap_or_else(|| battery.level());
+                    let changed = device.battery_level != level;
                     device.battery = battery;
+                    device.battery_level = level;
+                    if let Some(link) = self.link_of(&sn).filter(|_| changed) {
+                        self.send_battery(link);
+                    }
                 }
             }
-            ChannelInfo::Reset => {
-                if self.settings.load().send_reset && self.last_reset.elapsed().as_secs() >= 2 {
-                    self.last_reset = Instant::now();
-                    self.send_reset();
+            ChannelInfo::Buttons(held) => {
+                let action = self.devices.get_mut(&sn).and_then(|device| {
+                    device
+                        .buttons
+                        .update(held, now, &self.settings.load().bindings)
+                });
+                if let Some(action) = action {
+                    self.run_action(&sn, action);
                 }
             }
             ChannelInfo::Disconnected => {
+                self.settings
+                    .change_unsaved(|ws| ws.joycon_last_seen_set(sn.clone()));
                 if let Some(device) = self.devices.get_mut(&sn) {
                     device.imu_times = vec![];
                     device.status = DeviceStatus::Disconnected;
@@ -304,10 +1075,36 @@ impl Communication {
         }
     }
 
+    fn check_long_presses(&mut self) {
+        let now = self.clock.now();
+        let actions: Vec<_> = {
+            let settings = self.settings.load();
+            self.devices
+                .iter_mut()
+                .filter_map(|(sn, device)| {
+                    let action = device.buttons.tick(now, &settings.bindings)?;
+                    Some((sn.clone(), action))
+                })
+                .collect()
+        };
+        for (sn, action) in actions {
+            self.run_action(&sn, action);
+        }
+    }
+
     fn update_statuses(&mut self) {
-        let discard_before = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        let now = self.clock.now();
+        let discard_before = now.checked_sub(Duration::from_secs(1)).unwrap();
         for device in self.devices.values_mut() {
             device.imu_times.retain(|t| t > &discard_before);
+            device.imu_jitter = jitter(&device.imu_times);
+            let (since, sent) = device.rate_sample;
+            let elapsed = now.saturating_duration_since(since);
+            if elapsed >= Duration::from_secs(1) {
+                device.packets_per_second =
+                    (device.packets_sent - sent) as f32 / elapsed.as_secs_f32();
+                device.rate_sample = (now, device.packets_sent);
+            }
             match device.imu_times.len() {
                 x if x >= 55 => {
                     device.status = DeviceStatus::Healthy;
@@ -324,75 +1121,233 @@ impl Communication {
         }
     }
 
-    pub fn main_loop(&mut self) {
-        let mut buf = [0; 512];
+    /// Tells the servers when a controller disconnects, stops sending IMU data or recovers.
+    fn report_sensor_statuses(&mut self) {
+        let now = self.clock.now();
+        for (sn, device) in &mut self.devices {
+            let status = device.sensor_status(now);
+            if status == device.reported {
+                continue;
+            }
+            device.reported = status;
+            if let Some(link) = self.links.iter_mut().find(|l| l.carries(sn)) {
+                for server in &mut link.servers {
+                    device.send_sensor_info(&*link.socket, server);
+                }
+            }
+        }
+    }
 
+    pub fn main_loop(&mut self) {
         // Spin sleeper with 1ns accuracy. The accuracy is backwards, it means that a request for
         // X sleep will actually sleep for X - 1ns then spin for 1ns max.
         // It is used here because it also sets the minimum Windows sleep time to 1ms instead of 15ms.
         let light_sleeper = spin_sleep::SpinSleeper::new(1)
             .with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
 
-        let mut last_ui_send = Instant::now();
-
         loop {
-            if self.connected != ServerStatus::Connected
-                && self.last_handshake.elapsed().as_secs() >= 3
-            {
-                self.last_handshake = Instant::now();
-                self.send_handshake();
-                for device in self.devices.values().sorted_by_key(|d| d.send_id) {
-                    device.handshake(&self.socket, &self.address);
+            if !self.step() {
+                light_sleeper.sleep(Duration::from_millis(2));
+            }
+        }
+    }
+
+    /// A stray packet must not make a server look alive, so it is only counted.
+    fn ignore_packet(&mut self, from: SocketAddr) {
+        let count = self.ignored.entry(from).or_default();
+        if *count == 0 {
+            println!("[WARNING] Server - Ignoring packets from unexpected address {from}.");
+        }
+        *count += 1;
+    }
+
+    fn log_decode_error(&mut self, from: SocketAddr, error: &DecodeError) {
+        if let DecodeError::UnknownId(id) = error {
+            if !self.unknown_ids.insert(*id) {
+                return;
+            }
+        }
+        println!("[WARNING] Server - Could not parse packet from {from}: {error}.");
+    }
+
+    fn receive_packet(
+        &mut self,
+        link: usize,
+        index: usize,
+        packet: Result<ServerPacket, DecodeError>,
+        bytes: &[u8],
+    ) {
+        match self.links[link].servers[index].status {
+            ServerStatus::Disconnected => {
+                self.links[link].servers[index].status = ServerStatus::Unknown;
+                self.send_servers();
+            }
+            // Can't answer anything until the retry
+            ServerStatus::Error(_) => return,
+            _ => {}
+        }
+        let now = self.clock.now();
+        let link = &mut self.links[link];
+        let server = &mut link.servers[index];
+        match packet {
+            Ok(ServerPacket::Ping { id: _ }) => {
+                server.last_ping = now;
+                server.send_bytes(&*link.socket, bytes);
+            }
+            Ok(ServerPacket::Heartbeat) => {
+                server.last_ping = now;
+            }
+            Ok(ServerPacket::HandshakeResponse) => {
+                if server.status != ServerStatus::Connected {
+                    // The server answers with its own flags
+                    server.send_feature_flags(&*link.socket, now);
+                    server.status = ServerStatus::Connected;
+                    server.last_ping = now;
+                    server.retry_delay = FIRST_RETRY;
+                    self.send_servers();
                 }
             }
-            while let Ok(len) = self.socket.recv(&mut buf) {
-                if self.connected == ServerStatus::Disconnected {
-                    self.connected = ServerStatus::Unknown;
-                    self.server_tx.send(self.connected).ok();
+            Ok(ServerPacket::FeatureFlags { flags, .. }) => {
+                server.features = Some(flags);
+                if let Some(sent) = server.probe_sent.take() {
+                    server.rtt = Some(now.saturating_duration_since(sent));
+                    self.send_servers();
                 }
-                let b = PacketType::from_bytes((&buf, 0));
-                match b {
-                    Ok((_, PacketType::Ping { id: _ })) => {
-                        self.last_ping = Instant::now();
-                        self.socket.send_to(&buf[0..len], self.address).unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    /// One iteration of the main loop. Returns false if there was nothing to do.
+    pub fn step(&mut self) -> bool {
+        let mut buf = [0; 512];
+
+        self.check_addresses();
+        self.check_sensor_ids();
+        self.check_send_failures();
+        self.check_recording();
+        let discover = self.settings.load().discover_server;
+        let now = self.clock.now();
+        for link in 0..self.links.len() {
+            for index in 0..self.links[link].servers.len() {
+                let Link {
+                    socket,
+                    mac_address,
+                    servers,
+                    ..
+                } = &mut self.links[link];
+                let server = &mut servers[index];
+                if matches!(
+                    server.status,
+                    ServerStatus::Disconnected | ServerStatus::Unknown
+                ) && now
+                    .saturating_duration_since(server.last_handshake)
+                    .as_secs()
+                    >= 3
+                {
+                    server.last_handshake = now;
+                    server.handshake(&**socket, *mac_address, discover);
+                    self.handshake_devices(link, index);
+                }
+            }
+        }
+        for link in 0..self.links.len() {
+            while let Ok((len, from)) = self.links[link].socket.recv_from(&mut buf) {
+                let packet = ServerPacket::decode(&buf[0..len]);
+                let servers = &mut self.links[link].servers;
+                let index = match servers.iter().position(|s| s.address == from) {
+                    Some(index) => index,
+                    None if discover && matches!(packet, Ok(ServerPacket::HandshakeResponse)) => {
+                        // Lock onto the first server that answered the broadcast
+                        let Some(index) = servers.iter().position(|s| {
+                            matches!(s.status, ServerStatus::Disconnected | ServerStatus::Unknown)
+                        }) else {
+                            self.ignore_packet(from);
+                            continue;
+                        };
+                        servers[index].address = from;
+                        self.handshake_devices(link, index);
+                        index
                     }
-                    Ok((_, PacketType::HandshakeResponse)) => {
-                        self.connected = ServerStatus::Connected;
-                        self.server_tx.send(self.connected).ok();
+                    None => {
+                        self.ignore_packet(from);
+                        continue;
                     }
-                    _ => {}
+                };
+                if let Err(error) = &packet {
+                    self.log_decode_error(from, error);
                 }
+                self.receive_packet(link, index, packet, &buf[0..len]);
             }
-            if self.connected != ServerStatus::Disconnected
-                && self.last_ping.elapsed().as_secs() >= 3
-            {
-                self.connected = ServerStatus::Disconnected;
-                self.server_tx.send(self.connected).ok();
-            }
+        }
 
-            let messages: Vec<_> = self.receive.try_iter().collect();
-            if !messages.is_empty() || last_ui_send.elapsed().as_millis() > 100 {
-                for msg in messages {
-                    self.parse_message(msg);
+        let now = self.clock.now();
+        for link in &mut self.links {
+            for server in &mut link.servers {
+                if matches!(
+                    server.status,
+                    ServerStatus::Unknown | ServerStatus::Connected
+                ) && now.saturating_duration_since(server.last_ping).as_secs() >= 3
+                {
+                    server.disconnect(ServerStatus::Disconnected);
                 }
-
-                self.update_statuses();
-
-                last_ui_send = Instant::now();
-                let mut statuses = Vec::new();
-                for (serial_number, device) in &self.devices {
-                    statuses.push(Status {
-                        rotation: device.imu.euler_angles_deg(),
-                        design: device.design.clone(),
-                        serial_number: serial_number.clone(),
-                        battery: device.battery,
-                        status: device.status,
-                    });
+                if server.status == ServerStatus::Connected
+                    && now.saturating_duration_since(server.last_probe).as_secs() >= 2
+                {
+                    server.send_feature_flags(&*link.socket, now);
                 }
-                self.status_tx.send(statuses).ok();
-            } else {
-                light_sleeper.sleep(Duration::from_millis(2));
             }
         }
+        self.check_send_failures();
+
+        if self.elapsed(self.last_battery).as_secs() >= 10 {
+            let connected: Vec<_> = (0..self.links.len())
+                .filter(|&l| {
+                    self.links[l]
+                        .servers
+                        .iter()
+                        .any(|s| s.status == ServerStatus::Connected)
+                })
+                .collect();
+            if !connected.is_empty() {
+                self.last_battery = self.clock.now();
+            }
+            for link in connected {
+                self.send_battery(link);
+            }
+        }
+
+        let messages: Vec<_> = self.receive.try_iter().collect();
+        if messages.is_empty() && self.elapsed(self.last_ui_send).as_millis() <= 100 {
+            return false;
+        }
+        for msg in messages {
+            self.record(&msg);
+            self.parse_message(msg);
+        }
+        self.flush_recording();
+        self.flush_pending();
+        self.check_long_presses();
+        self.update_statuses();
+        self.report_sensor_statuses();
+        self.check_send_failures();
+
+        self.last_ui_send = self.clock.now();
+        let mut statuses = Vec::new();
+        for (serial_number, device) in &self.devices {
+            statuses.push(Status {
+                rotation: device.imu.euler_angles_deg(),
+                design: device.design.clone(),
+                serial_number: serial_number.clone(),
+                battery: device.battery,
+                status: device.status,
+                packets_sent: device.packets_sent,
+                packets_per_second: device.packets_per_second,
+                imu_jitter: device.imu_jitter,
+                paused: device.paused,
+            });
+        }
+        self.status_tx.send(statuses).ok();
+        true
     }
 }
@@ -0,0 +1,228 @@
+use nalgebra::{Matrix3x4, Quaternion, UnitQuaternion, Vector3};
+#[cfg(feature = "vqf")]
+use vqf_cxx::{VQFBuilder, VQF};
+
+use crate::settings::{FilterKind, VqfParams};
+
+/// Sensor fusion of gyro and accelerometer into an orientation.
+pub trait OrientationFilter {
+    /// Gyro in radians/s, accelerometer in g, `dt` in seconds since the previous frame.
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64);
+    /// Rotation from the controller to the world.
+    fn rotation(&self) -> UnitQuaternion<f64>;
+    /// Filters that have no use for the VQF settings ignore them.
+    fn set_params(&mut self, _params: VqfParams) {}
+    /// Forgets the gyro bias the filter learned by itself, called when the calibrated bias that
+    /// is taken off the gyro changes.
+    fn reset_bias(&mut self) {}
+}
+
+/// A new filter, starting from `rotation`.
+#[cfg_attr(not(feature = "vqf"), allow(unused_variables))]
+pub fn new_filter(
+    kind: FilterKind,
+    params: VqfParams,
+    rotation: UnitQuaternion<f64>,
+) -> Box<dyn OrientationFilter> {
+    match kind {
+        #[cfg(feature = "vqf")]
+        FilterKind::Vqf => Box::new(Vqf::new(params, rotation)),
+        #[cfg(not(feature = "vqf"))]
+        FilterKind::Vqf => {
+            println!("[WARNING] IMU - Built without VQF, using Madgwick instead.");
+            Box::new(Madgwick::new(rotation))
+        }
+        FilterKind::Madgwick => Box::new(Madgwick::new(rotation)),
+        FilterKind::Mahony => Box::new(Mahony::new(rotation)),
+    }
+}
+
+#[cfg(feature = "vqf")]
+pub struct Vqf {
+    vqf: VQF,
+    params: VqfParams,
+    /// VQF always starts facing forward, this turns it to the heading it started from. The tilt
+    /// comes from the accelerometer within a few frames.
+    heading: UnitQuaternion<f64>,
+    rotation: UnitQuaternion<f64>,
+    /// Sample periods that passed but were not fed to the filter yet.
+    behind: f64,
+}
+#[cfg(feature = "vqf")]
+impl Vqf {
+    fn new(params: VqfParams, rotation: UnitQuaternion<f64>) -> Self {
+        Self {
+            vqf: Self::build(params),
+            params,
+            heading: UnitQuaternion::from_axis_angle(&Vector3::z_axis(), rotation.euler_angles().2),
+            rotation,
+            behind: 0.0,
+        }
+    }
+    fn build(params: VqfParams) -> VQF {
+        VQFBuilder::new(params.sample_period)
+            .tau_acc(params.tau_acc)
+            .motion_bias_est_enabled(params.motion_bias_estimation)
+            .rest_th_gyr(params.rest_threshold_gyro)
+            .rest_th_acc(params.rest_threshold_accel)
+            .build()
+    }
+}
+#[cfg(feature = "vqf")]
+impl OrientationFilter for Vqf {
+    // VQF runs at the fixed sample period of its settings, lost frames are made up by repeating
+    // this one
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        self.behind += dt / self.params.sample_period;
+        let steps = self.behind.round().max(0.0);
+        self.behind -= steps;
+        for _ in 0..steps as usize {
+            self.vqf.update_6dof(&gyro.data.0[0], &acc.data.0[0]);
+        }
+        self.rotation = self.heading * UnitQuaternion::new_unchecked(self.vqf.get_quat_6d().into());
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        self.rotation
+    }
+    /// The filter state carries over, so the orientation is kept.
+    fn set_params(&mut self, params: VqfParams) {
+        if params == self.params {
+            return;
+        }
+        let state = self.vqf.get_state();
+        self.vqf = Self::build(params);
+        self.vqf.set_state(&state);
+        self.params = params;
+    }
+    fn reset_bias(&mut self) {
+        let mut state = self.vqf.get_state();
+        state.bias = [0.0; 3];
+        self.vqf.set_state(&state);
+    }
+}
+
+/// Gravity in the controller frame, as the rotation expects the accelerometer to measure it.
+fn expected_gravity(q: &UnitQuaternion<f64>) -> Vector3<f64> {
+    q.inverse_transform_vector(&Vector3::z())
+}
+
+fn integrate(q: &UnitQuaternion<f64>, rate: Quaternion<f64>, dt: f64) -> UnitQuaternion<f64> {
+    UnitQuaternion::from_quaternion(q.into_inner() + rate * dt)
+}
+
+// Gyro error in radians/s, the default of the original paper
+const MADGWICK_BETA: f64 = 0.1;
+
+/// Madgwick's gradient descent filter.
+pub struct Madgwick {
+    rotation: UnitQuaternion<f64>,
+}
+impl Madgwick {
+    fn new(rotation: UnitQuaternion<f64>) -> Self {
+        Self { rotation }
+    }
+}
+impl OrientationFilter for Madgwick {
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        let q = self.rotation;
+        let mut rate = q.into_inner() * Quaternion::from_imag(gyro) * 0.5;
+        if let Some(acc) = acc.try_normalize(f64::EPSILON) {
+            let (w, x, y, z) = (q.w, q.i, q.j, q.k);
+            let error = expected_gravity(&q) - acc;
+            #[rustfmt::skip]
+            let jacobian = Matrix3x4::new(
+                -2.0 * y, 2.0 * z, -2.0 * w, 2.0 * x,
+                2.0 * x, 2.0 * w, 2.0 * z, 2.0 * y,
+                0.0, -4.0 * x, -4.0 * y, 0.0,
+            );
+            // In w, x, y, z order
+            let step = jacobian.transpose() * error;
+            if let Some(step) = step.try_normalize(f64::EPSILON) {
+                rate -= Quaternion::new(step[0], step[1], step[2], step[3]) * MADGWICK_BETA;
+            }
+        }
+        self.rotation = integrate(&q, rate, dt);
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        self.rotation
+    }
+}
+
+// Proportional and integral gains of the accelerometer correction
+const MAHONY_KP: f64 = 1.0;
+const MAHONY_KI: f64 = 0.01;
+
+/// Mahony's complementary filter, it also learns the gyro bias.
+pub struct Mahony {
+    rotation: UnitQuaternion<f64>,
+    integral: Vector3<f64>,
+}
+impl Mahony {
+    fn new(rotation: UnitQuaternion<f64>) -> Self {
+        Self {
+            rotation,
+            integral: Vector3::zeros(),
+        }
+    }
+}
+impl OrientationFilter for Mahony {
+    fn update(&mut self, gyro: Vector3<f64>, acc: Vector3<f64>, dt: f64) {
+        let q = self.rotation;
+        let mut gyro = gyro;
+        if let Some(acc) = acc.try_normalize(f64::EPSILON) {
+            let error = acc.cross(&expected_gravity(&q));
+            self.integral += error * MAHONY_KI * dt;
+            gyro += error * MAHONY_KP + self.integral;
+        }
+        let rate = q.into_inner() * Quaternion::from_imag(gyro) * 0.5;
+        self.rotation = integrate(&q, rate, dt);
+    }
+    fn rotation(&self) -> UnitQuaternion<f64> {
+        self.rotation
+    }
+    fn reset_bias(&mut self) {
+        self.integral = Vector3::zeros();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.005;
+
+    fn filters(rotation: UnitQuaternion<f64>) -> [Box<dyn OrientationFilter>; 2] {
+        [
+            Box::new(Madgwick::new(rotation)),
+            Box::new(Mahony::new(rotation)),
+        ]
+    }
+
+    #[test]
+    fn converges_to_gravity() {
+        let tilted = UnitQuaternion::from_euler_angles(0.5, -0.3, 1.0);
+        for mut filter in filters(tilted) {
+            // Lying flat and still for 10 seconds
+            for _ in 0..2000 {
+                filter.update(Vector3::zeros(), Vector3::z(), DT);
+            }
+            let tilt = expected_gravity(&filter.rotation()).angle(&Vector3::z());
+            assert!(tilt.to_degrees() < 1.0, "{}", tilt.to_degrees());
+        }
+    }
+
+    #[test]
+    fn integrates_gyro() {
+        for axis in [Vector3::x_axis(), Vector3::y_axis(), Vector3::z_axis()] {
+            for mut filter in filters(UnitQuaternion::identity()) {
+                // Without an accelerometer reading there is nothing to correct with
+                for _ in 0..200 {
+                    filter.update(axis.into_inner() * 0.5, Vector3::zeros(), DT);
+                }
+                let expected = UnitQuaternion::from_axis_angle(&axis, 0.5);
+                let error = filter.rotation().angle_to(&expected);
+                assert!(error < 1e-3, "{axis:?} {error}");
+            }
+        }
+    }
+}
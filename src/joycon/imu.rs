@@ -1,8 +1,14 @@
-use nalgebra::{Quaternion, UnitQuaternion, Vector3};
-use vqf_cxx::{VQFBuilder, VQF};
+use std::time::{Duration, Instant};
+
+use nalgebra::{UnitQuaternion, Vector3};
+
+use super::filter::{new_filter, OrientationFilter};
+use crate::settings::{FilterKind, VqfParams};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct JoyconAxisData {
+    /// When the controller took the sample.
+    pub timestamp: Instant,
     pub accel_x: f64,
     pub accel_y: f64,
     pub accel_z: f64,
@@ -11,24 +17,168 @@ pub struct JoyconAxisData {
     pub gyro_z: f64,
 }
 
+/// Time between two frames of a joycon report.
+pub const FRAME_PERIOD: Duration = Duration::from_millis(5);
+
+/// Timestamps of the three frames in a report whose newest frame is at `newest`.
+pub fn report_timestamps(newest: Instant) -> [Instant; 3] {
+    [2, 1, 0].map(|i| newest.checked_sub(FRAME_PERIOD * i).unwrap_or(newest))
+}
+
+// Longer without data is a gap, like a reconnect, not a few lost reports
+const MAX_GAP: Duration = Duration::from_millis(100);
+
+/// Sample times of the reports of one controller, from the 8 bit timer in every report.
+///
+/// Bluetooth delivers reports late and in bunches, the timer still counts the time between them
+/// and over lost reports. It counts frame periods and wraps after 1.28 seconds, so it is
+/// anchored to the time the reports arrive at:
+/// - A report can't be sampled after it arrived, which moves the anchor to the fastest report.
+/// - When the timer falls behind by more than a gap it wrapped, and the times start over.
+#[derive(Debug, Default)]
+pub struct ReportClock {
+    /// Timer and time of the newest frame of the previous report.
+    last: Option<(u8, Instant)>,
+}
+impl ReportClock {
+    pub fn timestamps(&mut self, timer: u8, received: Instant) -> [Instant; 3] {
+        let newest = match self.last {
+            Some((last_timer, last_time)) => {
+                let ticks = timer.wrapping_sub(last_timer);
+                let time = last_time + FRAME_PERIOD * u32::from(ticks);
+                if time > received || received.duration_since(time) > MAX_GAP {
+                    received
+                } else {
+                    time
+                }
+            }
+            None => received,
+        };
+        self.last = Some((timer, newest));
+        report_timestamps(newest)
+    }
+}
+
+// How long the controller has to lie still
+const REST_TIME: Duration = Duration::from_secs(3);
+// Largest difference from the average while resting, in radians/s and g
+const REST_GYRO: f64 = 0.02;
+const REST_ACCEL: f64 = 0.03;
+// A larger bias means the controller is turning steadily, like on a turntable. Same as the 2°/s
+// VQF allows while resting, Joy-Con biases are well below.
+const MAX_BIAS: f64 = 0.035;
+
+/// Finds the gyro bias by averaging the gyro while the controller lies still.
+#[derive(Default)]
+struct BiasCalibration {
+    /// First frame of the current rest.
+    since: Option<Instant>,
+    frames: usize,
+    gyro_sum: Vector3<f64>,
+    accel_sum: Vector3<f64>,
+    /// Calibrated once in this rest, waits for the controller to move.
+    done: bool,
+}
+impl BiasCalibration {
+    fn update(
+        &mut self,
+        timestamp: Instant,
+        gyro: Vector3<f64>,
+        acc: Vector3<f64>,
+    ) -> Option<Vector3<f64>> {
+        if self.frames > 0 {
+            let n = self.frames as f64;
+            if (gyro - self.gyro_sum / n).amax() > REST_GYRO
+                || (acc - self.accel_sum / n).amax() > REST_ACCEL
+            {
+                *self = Self::default();
+            }
+        }
+        if self.done {
+            return None;
+        }
+        let since = *self.since.get_or_insert(timestamp);
+        self.frames += 1;
+        self.gyro_sum += gyro;
+        self.accel_sum += acc;
+        if timestamp.saturating_duration_since(since) < REST_TIME {
+            return None;
+        }
+        self.done = true;
+        let bias = self.gyro_sum / self.frames as f64;
+        (bias.amax() <= MAX_BIAS).then_some(bias)
+    }
+}
+
 pub struct Imu {
-    vqf: VQF,
+    filter: Box<dyn OrientationFilter>,
+    kind: FilterKind,
+    params: VqfParams,
     pub rotation: UnitQuaternion<f64>,
+    /// Subtracted from the gyro before it goes into the filter.
+    bias: Vector3<f64>,
+    calibration: BiasCalibration,
+    last_timestamp: Option<Instant>,
 }
 impl Imu {
-    pub fn new() -> Self {
+    /// Starts from a gyro bias that was saved earlier, instead of learning it again.
+    pub fn new(bias: [f64; 3], kind: FilterKind, params: VqfParams) -> Self {
+        let rotation = UnitQuaternion::identity();
         Self {
-            vqf: VQFBuilder::new(0.005f64).build(),
-            rotation: UnitQuaternion::new_unchecked(Quaternion::new(
-                1.0f64, 0.0f64, 0.0f64, 0.0f64,
-            )),
+            filter: new_filter(kind, params, rotation),
+            kind,
+            params,
+            rotation,
+            bias: bias.into(),
+            calibration: BiasCalibration::default(),
+            last_timestamp: None,
+        }
+    }
+    /// Applies changed filter settings. A new algorithm starts from the current orientation, new
+    /// VQF settings keep the filter state.
+    pub fn set_filter(&mut self, kind: FilterKind, params: VqfParams) {
+        if kind != self.kind {
+            self.filter = new_filter(kind, params, self.rotation);
+            self.kind = kind;
+        } else if params != self.params {
+            self.filter.set_params(params);
         }
+        self.params = params;
     }
-    pub fn update(&mut self, frame: JoyconAxisData) {
-        let gyro = Vector3::new(frame.gyro_x, frame.gyro_y, frame.gyro_z);
+    /// Returns a new gyro bias after the controller was still for a few seconds, it is used
+    /// right away.
+    pub fn update(&mut self, frame: JoyconAxisData) -> Option<[f64; 3]> {
+        let raw_gyro = Vector3::new(frame.gyro_x, frame.gyro_y, frame.gyro_z);
         let acc = Vector3::new(frame.accel_x, frame.accel_y, frame.accel_z);
-        self.vqf.update_6dof(&gyro.data.0[0], &acc.data.0[0]);
-        self.rotation = UnitQuaternion::new_unchecked(self.vqf.get_quat_6d().into());
+        let new_bias = self.calibration.update(frame.timestamp, raw_gyro, acc);
+        if let Some(bias) = new_bias {
+            // What the filter learned is relative to the old bias
+            self.bias = bias;
+            self.filter.reset_bias();
+        }
+        let dt = self.frame_dt(frame.timestamp);
+        self.filter.update(raw_gyro - self.bias, acc, dt);
+        self.rotation = self.filter.rotation();
+        new_bias.map(Into::into)
+    }
+    /// Seconds since the previous frame. Lost reports are integrated over, but turning for the
+    /// whole of a gap would be a guess, so it counts as one sample period.
+    fn frame_dt(&mut self, timestamp: Instant) -> f64 {
+        let Some(last) = self.last_timestamp else {
+            self.last_timestamp = Some(timestamp);
+            return self.params.sample_period;
+        };
+        // Frames out of order count as simultaneous
+        self.last_timestamp = Some(last.max(timestamp));
+        let dt = timestamp.saturating_duration_since(last);
+        if dt > MAX_GAP {
+            println!(
+                "[WARNING] IMU - No data for {} ms, skipping the gap.",
+                dt.as_millis()
+            );
+            return self.params.sample_period;
+        }
+        dt.as_secs_f64()
     }
     // euler_angles: roll, pitch, yaw
     pub fn euler_angles_deg(&self) -> (f64, f64, f64) {
@@ -36,3 +186,89 @@ impl Imu {
         (ea.0.to_degrees(), ea.1.to_degrees(), ea.2.to_degrees())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reports are sampled 15 ms apart but arrive late, some of them in bunches
+    const LATENCY_MS: [u64; 8] = [5, 30, 18, 18, 40, 9, 25, 25];
+
+    fn newest(clock: &mut ReportClock, timer: u8, received: Instant) -> Instant {
+        clock.timestamps(timer, received)[2]
+    }
+
+    #[test]
+    fn report_times_ignore_arrival_jitter() {
+        let start = Instant::now();
+        let mut clock = ReportClock::default();
+        for (i, latency) in (0..40).zip(LATENCY_MS.iter().cycle()) {
+            let sampled = start + FRAME_PERIOD * 3 * i;
+            let received = sampled + Duration::from_millis(*latency);
+            let timer = (i * 3) as u8;
+            let expected = start + Duration::from_millis(5) + FRAME_PERIOD * 3 * i;
+            assert_eq!(
+                clock.timestamps(timer, received),
+                [
+                    expected - FRAME_PERIOD * 2,
+                    expected - FRAME_PERIOD,
+                    expected
+                ],
+                "report {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn report_times_follow_the_fastest_report() {
+        let start = Instant::now();
+        let mut clock = ReportClock::default();
+        newest(&mut clock, 0, start + Duration::from_millis(40));
+        // This one came quicker, so the first one was sampled earlier than it arrived
+        let second = start + FRAME_PERIOD * 3 + Duration::from_millis(10);
+        assert_eq!(newest(&mut clock, 3, second), second);
+        assert_eq!(
+            newest(&mut clock, 6, second + Duration::from_millis(30)),
+            second + FRAME_PERIOD * 3
+        );
+    }
+
+    #[test]
+    fn report_times_count_lost_reports() {
+        let start = Instant::now();
+        let mut clock = ReportClock::default();
+        newest(&mut clock, 250, start);
+        // Two reports are lost, and the timer wraps
+        let third = newest(&mut clock, 3, start + Duration::from_millis(60));
+        assert_eq!(third, start + FRAME_PERIOD * 9);
+
+        // The timer wrapped on its own, only the arrival tells how long it was
+        let later = start + Duration::from_secs(5);
+        assert_eq!(newest(&mut clock, 6, later), later);
+    }
+
+    #[test]
+    fn jittered_reports_integrate_all_frames() {
+        let start = Instant::now();
+        let mut clock = ReportClock::default();
+        let mut imu = Imu::new([0.0; 3], FilterKind::Madgwick, VqfParams::default());
+        for (i, latency) in (0..100).zip(LATENCY_MS.iter().cycle()) {
+            let received = start + FRAME_PERIOD * 3 * i + Duration::from_millis(*latency);
+            for timestamp in clock.timestamps((i * 3) as u8, received) {
+                imu.update(JoyconAxisData {
+                    timestamp,
+                    accel_x: 0.0,
+                    accel_y: 0.0,
+                    accel_z: 1.0,
+                    gyro_x: 0.0,
+                    gyro_y: 0.0,
+                    gyro_z: 0.5,
+                });
+            }
+        }
+        // No frame was dropped or counted twice
+        let expected = 0.5 * (FRAME_PERIOD * 300).as_secs_f64();
+        let yaw = imu.rotation.euler_angles().2;
+        assert!((yaw - expected).abs() < 1e-3, "{yaw} {expected}");
+    }
+}
@@ -1,14 +1,15 @@
 use super::communication::ChannelData;
-use super::imu::JoyconAxisData;
+use super::imu::{JoyconAxisData, ReportClock};
 use super::{Battery, ChannelInfo, JoyconDesign, JoyconDesignType};
-use crate::settings;
+use crate::settings::{self, Button};
 use joycon_rs::joycon::device::calibration::imu::IMUCalibration;
 use joycon_rs::joycon::lights::{LightUp, Lights};
 use joycon_rs::prelude::input_report_mode::BatteryLevel;
 use joycon_rs::prelude::*;
+use std::collections::BTreeSet;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Gyro: 2000dps
 // Accel: 8G
@@ -39,6 +40,31 @@ fn convert_battery(battery: BatteryLevel) -> Battery {
     }
 }
 
+fn convert_button(button: Button) -> Buttons {
+    match button {
+        Button::Up => Buttons::Up,
+        Button::Down => Buttons::Down,
+        Button::Left => Buttons::Left,
+        Button::Right => Buttons::Right,
+        Button::A => Buttons::A,
+        Button::B => Buttons::B,
+        Button::X => Buttons::X,
+        Button::Y => Buttons::Y,
+        Button::L => Buttons::L,
+        Button::R => Buttons::R,
+        Button::Zl => Buttons::ZL,
+        Button::Zr => Buttons::ZR,
+        Button::Sl => Buttons::SL,
+        Button::Sr => Buttons::SR,
+        Button::Minus => Buttons::Minus,
+        Button::Plus => Buttons::Plus,
+        Button::Home => Buttons::Home,
+        Button::Capture => Buttons::Capture,
+        Button::LStick => Buttons::LStick,
+        Button::RStick => Buttons::RStick,
+    }
+}
+
 fn convert_design(device_type: &JoyConDeviceType) -> JoyconDesignType {
     match device_type {
         JoyConDeviceType::JoyConL => JoyconDesignType::Left,
@@ -68,26 +94,41 @@ fn joycon_listen_loop(
         JoyConDeviceType::JoyConL | JoyConDeviceType::ProCon => |v| v,
     };
     let mut last_battery = None;
+    let mut last_buttons = BTreeSet::new();
+    let mut clock = ReportClock::default();
     loop {
         match standard.read_input_report() {
             Ok(report) => {
+                let received = Instant::now();
                 if report.common.input_report_id == 48 {
                     if Some(report.common.battery.level) != last_battery {
                         last_battery = Some(report.common.battery.level);
                         tx.send(ChannelData::new(
                             serial_number.clone(),
-                            ChannelInfo::Battery(convert_battery(report.common.battery.level)),
+                            ChannelInfo::Battery(
+                                convert_battery(report.common.battery.level),
+                                None,
+                            ),
                         ))
                         .unwrap();
                     }
-                    if report.common.pushed_buttons.contains(Buttons::Up)
-                        || report.common.pushed_buttons.contains(Buttons::B)
-                    {
-                        tx.send(ChannelData::new(serial_number.clone(), ChannelInfo::Reset))
-                            .unwrap();
+                    let buttons: BTreeSet<_> = Button::ALL
+                        .into_iter()
+                        .filter(|b| report.common.pushed_buttons.contains(convert_button(*b)))
+                        .collect();
+                    if buttons != last_buttons {
+                        last_buttons = buttons.clone();
+                        tx.send(ChannelData::new(
+                            serial_number.clone(),
+                            ChannelInfo::Buttons(buttons),
+                        ))
+                        .unwrap();
                     }
                     let gyro_scale_factor = settings.load().joycon_scale_get(&serial_number);
+                    let mut timestamps =
+                        clock.timestamps(report.common.timer, received).into_iter();
                     let imu_data = report.extra.data.map(|data| JoyconAxisData {
+                        timestamp: timestamps.next().unwrap(),
                         accel_x: acc(data.accel_x, calib.0[0]),
                         accel_y: neg_right(acc(data.accel_y, calib.0[1])),
                         accel_z: neg_right(acc(data.accel_z, calib.0[2])),
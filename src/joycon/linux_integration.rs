@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
     sync::{mpsc, Arc},
     time::{Duration, Instant, SystemTime},
 };
@@ -8,10 +8,11 @@ use tokio::{sync::Mutex, time::interval};
 use evdev::{enumerate, EventStream, InputEventKind, Key};
 use upower_dbus::{DeviceProxy, UPowerProxy};
 
-use crate::settings;
+use crate::settings::{self, Button};
 
 use super::{
-    imu::JoyconAxisData, Battery, ChannelData, ChannelInfo, JoyconDesign, JoyconDesignType,
+    imu::{JoyconAxisData, FRAME_PERIOD},
+    Battery, ChannelData, ChannelInfo, JoyconDesign, JoyconDesignType,
 };
 
 // Resolution definitions from hid-nintendo.c from linux:
@@ -44,6 +45,41 @@ fn convert_design(product_code: u16) -> JoyconDesignType {
     }
 }
 
+// A single Joy-Con reports SL and SR as the triggers of the other side, which it doesn't have.
+fn convert_button(key: Key, product_code: u16) -> Option<Button> {
+    Some(match (key, product_code) {
+        (Key::BTN_TR, USB_DEVICE_ID_NINTENDO_JOYCONL) => Button::Sl,
+        (Key::BTN_TR2, USB_DEVICE_ID_NINTENDO_JOYCONL) => Button::Sr,
+        (Key::BTN_TL, USB_DEVICE_ID_NINTENDO_JOYCONR) => Button::Sl,
+        (Key::BTN_TL2, USB_DEVICE_ID_NINTENDO_JOYCONR) => Button::Sr,
+        _ => return convert_key(key),
+    })
+}
+
+fn convert_key(key: Key) -> Option<Button> {
+    Some(match key {
+        Key::BTN_DPAD_UP => Button::Up,
+        Key::BTN_DPAD_DOWN => Button::Down,
+        Key::BTN_DPAD_LEFT => Button::Left,
+        Key::BTN_DPAD_RIGHT => Button::Right,
+        Key::BTN_EAST => Button::A,
+        Key::BTN_SOUTH => Button::B,
+        Key::BTN_NORTH => Button::X,
+        Key::BTN_WEST => Button::Y,
+        Key::BTN_TL => Button::L,
+        Key::BTN_TR => Button::R,
+        Key::BTN_TL2 => Button::Zl,
+        Key::BTN_TR2 => Button::Zr,
+        Key::BTN_SELECT => Button::Minus,
+        Key::BTN_START => Button::Plus,
+        Key::BTN_MODE => Button::Home,
+        Key::BTN_Z => Button::Capture,
+        Key::BTN_THUMBL => Button::LStick,
+        Key::BTN_THUMBR => Button::RStick,
+        _ => return None,
+    })
+}
+
 fn convert_battery(battery: upower_dbus::BatteryLevel) -> Battery {
     match battery {
         upower_dbus::BatteryLevel::Full | upower_dbus::BatteryLevel::High => Battery::Full,
@@ -56,14 +92,25 @@ fn convert_battery(battery: upower_dbus::BatteryLevel) -> Battery {
 
 async fn joycon_listener(tx: mpsc::Sender<ChannelData>, mut input: EventStream) {
     let mac = input.device().unique_name().unwrap().to_string(); // Joycons always have unique name
+    let product_code = input.device().input_id().product();
+
+    let mut held = BTreeSet::new();
 
     while let Ok(ev) = input.next_event().await {
         if let InputEventKind::Key(key) = ev.kind() {
-            // if DPAD_UP or BTN_SOUTH and button is lifted
-            if (key == Key::BTN_DPAD_UP || key == Key::BTN_SOUTH) && ev.value() == 0 {
+            let Some(button) = convert_button(key, product_code) else {
+                continue;
+            };
+            // 0 is released, 1 is pressed and 2 is autorepeat
+            let changed = match ev.value() {
+                0 => held.remove(&button),
+                1 => held.insert(button),
+                _ => false,
+            };
+            if changed {
                 tx.send(ChannelData {
                     serial_number: mac.clone(),
-                    info: ChannelInfo::Reset,
+                    info: ChannelInfo::Buttons(held.clone()),
                 })
                 .unwrap();
             }
@@ -77,6 +124,35 @@ async fn joycon_listener(tx: mpsc::Sender<ChannelData>, mut input: EventStream)
     .unwrap();
 }
 
+/// Event timestamps are wall clock time, the IMU runs on `Instant`.
+fn instant_of(time: SystemTime) -> Instant {
+    let age = SystemTime::now().duration_since(time).unwrap_or_default();
+    let now = Instant::now();
+    now.checked_sub(age).unwrap_or(now)
+}
+
+/// Sample times of the IMU frames of one controller.
+///
+/// The kernel gives all frames of a report the same event time, so frames sharing a time are
+/// spaced `FRAME_PERIOD` apart. A frame never goes back before the previous one.
+#[derive(Default)]
+struct FrameClock {
+    last: Option<(SystemTime, Instant)>,
+}
+
+impl FrameClock {
+    /// `time` is the event time `event` on the `Instant` clock.
+    fn timestamp(&mut self, event: SystemTime, time: Instant) -> Instant {
+        let time = match self.last {
+            Some((last_event, last)) if event == last_event => last + FRAME_PERIOD,
+            Some((_, last)) => time.max(last + FRAME_PERIOD),
+            None => time,
+        };
+        self.last = Some((event, time));
+        time
+    }
+}
+
 async fn imu_listener(
     tx: mpsc::Sender<ChannelData>,
     settings: settings::Handler,
@@ -84,6 +160,7 @@ async fn imu_listener(
 ) {
     let mac = input.device().unique_name().unwrap().to_string(); // Joycons always have unique name
     let mut imu_array = [JoyconAxisData {
+        timestamp: Instant::now(),
         accel_x: 0.0,
         accel_y: 0.0,
         accel_z: 0.0,
@@ -92,6 +169,7 @@ async fn imu_listener(
         gyro_z: 0.0,
     }; 3];
     let mut count = 0;
+    let mut clock = FrameClock::default();
     let mut sys_time = SystemTime::now();
     let mut last_event = input.device().get_abs_state().unwrap();
 
@@ -101,6 +179,8 @@ async fn imu_listener(
             last_event = input.device().get_abs_state().unwrap();
             continue;
         }
+        // The remembered event is from the previous timestamp
+        let timestamp = clock.timestamp(sys_time, instant_of(sys_time));
         sys_time = ev.timestamp();
 
         let gyro_scale_factor = settings.load().joycon_scale_get(&mac);
@@ -111,6 +191,7 @@ async fn imu_listener(
         let accel_axis = &axis[..3];
         let gyro_axis = &axis[3..6];
         imu_array[count] = JoyconAxisData {
+            timestamp,
             accel_x: acc(accel_axis[0].value),
             accel_y: acc(accel_axis[1].value),
             accel_z: acc(accel_axis[2].value),
@@ -144,10 +225,22 @@ async fn check_batteries(tx: mpsc::Sender<ChannelData>, macs: &HashSet<String>)
         let Ok(serial) = device.serial().await else { continue; };
 
         if macs.contains(&serial) {
-            let level = convert_battery(device.battery_level().await.unwrap());
+            // UPower reports 0-100, but not every kernel exposes more than the coarse level.
+            let percentage = device
+                .percentage()
+                .await
+                .ok()
+                .filter(|p| *p > 0.0)
+                .map(|p| (p / 100.0) as f32);
+            let level = match device.battery_level().await.unwrap() {
+                upower_dbus::BatteryLevel::Unknown | upower_dbus::BatteryLevel::None => {
+                    percentage.map_or(Battery::Empty, Battery::from_level)
+                }
+                level => convert_battery(level),
+            };
             tx.send(ChannelData {
                 serial_number: serial,
-                info: ChannelInfo::Battery(level),
+                info: ChannelInfo::Battery(level, percentage),
             })
             .unwrap();
         }
@@ -241,3 +334,43 @@ pub async fn spawn_thread(tx: mpsc::Sender<ChannelData>, settings: settings::Han
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_frames_of_one_report() {
+        let mut clock = FrameClock::default();
+        let (event, start) = (SystemTime::now(), Instant::now());
+        let report = FRAME_PERIOD * 3;
+
+        let mut times = Vec::new();
+        for i in 0..3 {
+            for _ in 0..3 {
+                times.push(clock.timestamp(event + report * i, start + report * i));
+            }
+        }
+        for (i, pair) in times.windows(2).enumerate() {
+            assert_eq!(pair[1] - pair[0], FRAME_PERIOD, "frame {}", i + 1);
+        }
+        assert_eq!(times[0], start);
+    }
+
+    #[test]
+    fn keeps_bunched_reports_in_order() {
+        let mut clock = FrameClock::default();
+        let (event, start) = (SystemTime::now(), Instant::now());
+        let early = Duration::from_millis(2);
+
+        for _ in 0..3 {
+            clock.timestamp(event, start);
+        }
+        // The next report arrives right after, before the previous frames are through
+        let bunched = clock.timestamp(event + early, start + early);
+        assert_eq!(bunched, start + FRAME_PERIOD * 3);
+        // A later report is on time again
+        let late = Duration::from_millis(40);
+        assert_eq!(clock.timestamp(event + late, start + late), start + late);
+    }
+}
@@ -1,13 +1,19 @@
 //mod ui;
+mod buttons;
+mod filter;
 mod imu;
 
+mod clock;
 mod communication;
+mod recorder;
+mod transport;
 pub use communication::*;
 
 mod integration;
 #[cfg(target_os = "linux")]
 mod linux_integration;
 use integration::spawn_thread;
+mod test_communication;
 mod test_integration;
 
 mod wrapper;
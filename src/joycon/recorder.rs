@@ -0,0 +1,306 @@
+//! Recordings of everything the controllers send, to find out later why a tracker misbehaved.
+//!
+//! A recording starts with [`MAGIC`] and the format version as a little-endian `u16`, followed by
+//! one record per [`ChannelData`]. All numbers are little-endian, strings are a `u8` length and
+//! UTF-8. A record is:
+//! - `u8` kind, the [`ChannelInfo`] variant
+//! - `u64` microseconds since the recording started
+//! - the serial number
+//! - the payload of the kind:
+//!   - Connected: `u8` design type and the color
+//!   - ImuData: three frames of an `i64` timestamp in microseconds since the recording started,
+//!     then accelerometer x, y, z and gyro x, y, z as `f32`
+//!   - Battery: `u8` level and the `f32` charge, NaN if unknown
+//!   - Buttons: `u8` count and the `u8` index in [`Button::ALL`] of every held button
+//!   - Disconnected: nothing
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    communication::{ChannelData, ChannelInfo},
+    imu::JoyconAxisData,
+    Battery, JoyconDesign, JoyconDesignType,
+};
+use crate::settings::Button;
+
+pub const MAGIC: &[u8; 8] = b"WRNGLIMU";
+pub const VERSION: u16 = 1;
+
+const CONNECTED: u8 = 0;
+const IMU_DATA: u8 = 1;
+const BATTERY: u8 = 2;
+const BUTTONS: u8 = 3;
+const DISCONNECTED: u8 = 4;
+
+const DESIGN_TYPES: [JoyconDesignType; 3] = [
+    JoyconDesignType::Left,
+    JoyconDesignType::Right,
+    JoyconDesignType::Pro,
+];
+const BATTERIES: [Battery; 5] = [
+    Battery::Empty,
+    Battery::Critical,
+    Battery::Low,
+    Battery::Medium,
+    Battery::Full,
+];
+
+pub struct Recorder {
+    file: BufWriter<File>,
+    path: PathBuf,
+    start: Instant,
+}
+impl Recorder {
+    pub fn create(path: &Path, start: Instant) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            start,
+        })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn write(&mut self, data: &ChannelData, now: Instant) -> io::Result<()> {
+        let mut record = vec![];
+        let time = now.saturating_duration_since(self.start).as_micros() as u64;
+        match &data.info {
+            ChannelInfo::Connected(design) => {
+                start_record(&mut record, CONNECTED, time, &data.serial_number);
+                record.push(index_of(&DESIGN_TYPES, &design.design_type));
+                put_str(&mut record, &design.color);
+            }
+            ChannelInfo::ImuData(frames) => {
+                start_record(&mut record, IMU_DATA, time, &data.serial_number);
+                for frame in frames {
+                    record.extend(micros_since(self.start, frame.timestamp).to_le_bytes());
+                    for value in [
+                        frame.accel_x,
+                        frame.accel_y,
+                        frame.accel_z,
+                        frame.gyro_x,
+                        frame.gyro_y,
+                        frame.gyro_z,
+                    ] {
+                        record.extend((value as f32).to_le_bytes());
+                    }
+                }
+            }
+            ChannelInfo::Battery(battery, charge) => {
+                start_record(&mut record, BATTERY, time, &data.serial_number);
+                record.push(index_of(&BATTERIES, battery));
+                record.extend(charge.unwrap_or(f32::NAN).to_le_bytes());
+            }
+            ChannelInfo::Buttons(held) => {
+                start_record(&mut record, BUTTONS, time, &data.serial_number);
+                record.push(held.len() as u8);
+                record.extend(held.iter().map(|b| index_of(&Button::ALL, b)));
+            }
+            ChannelInfo::Disconnected => {
+                start_record(&mut record, DISCONNECTED, time, &data.serial_number);
+            }
+        }
+        self.file.write_all(&record)
+    }
+    /// Hands the buffered records to the OS, so they are kept if Wrangler is killed.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn start_record(record: &mut Vec<u8>, kind: u8, time: u64, serial_number: &str) {
+    record.push(kind);
+    record.extend(time.to_le_bytes());
+    put_str(record, serial_number);
+}
+
+fn put_str(record: &mut Vec<u8>, s: &str) {
+    let bytes = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    record.push(bytes.len() as u8);
+    record.extend(bytes);
+}
+
+fn index_of<T: PartialEq>(all: &[T], value: &T) -> u8 {
+    all.iter().position(|v| v == value).unwrap_or_default() as u8
+}
+
+// Frames can be older than the recording
+fn micros_since(start: Instant, time: Instant) -> i64 {
+    match time.checked_duration_since(start) {
+        Some(after) => after.as_micros() as i64,
+        None => -(start.duration_since(time).as_micros() as i64),
+    }
+}
+
+/// A recorded [`ChannelData`], `time` after the recording started.
+pub struct Record {
+    pub time: Duration,
+    pub data: ChannelData,
+}
+
+/// Reads a recording, the frame timestamps are made relative to `start`. A record cut off at the
+/// end, like after a crash, is left out.
+#[cfg(test)]
+pub fn read(path: &Path, start: Instant) -> io::Result<Vec<Record>> {
+    let mut file = open(path)?;
+    let mut records = vec![];
+    while let Some(record) = next_record(&mut file, start)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Opens a recording and checks its header, the records follow.
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    let mut file = BufReader::new(File::open(path)?);
+    if &take::<8>(&mut file)? != MAGIC {
+        return Err(invalid("Not a Wrangler IMU recording".into()));
+    }
+    let version = u16::from_le_bytes(take(&mut file)?);
+    if version > VERSION {
+        return Err(invalid(format!(
+            "Recording version {version} is newer than this Wrangler"
+        )));
+    }
+    Ok(file)
+}
+
+// A record cut off at the end is the end of the recording too
+fn next_record(file: &mut impl Read, start: Instant) -> io::Result<Option<Record>> {
+    match read_record(file, start) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            println!("[WARNING] Recorder - The last record is cut off, leaving it out.");
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+fn read_record(file: &mut impl Read, start: Instant) -> io::Result<Option<Record>> {
+    let mut kind = [0];
+    if file.read(&mut kind)? == 0 {
+        return Ok(None);
+    }
+    let time = Duration::from_micros(u64::from_le_bytes(take(file)?));
+    let serial_number = take_str(file)?;
+    let info = match kind[0] {
+        CONNECTED => {
+            let design_type = take_index(file, &DESIGN_TYPES)?;
+            ChannelInfo::Connected(JoyconDesign {
+                color: take_str(file)?,
+                design_type,
+            })
+        }
+        IMU_DATA => {
+            let mut frames = vec![];
+            for _ in 0..3 {
+                let micros = i64::from_le_bytes(take(file)?);
+                let offset = Duration::from_micros(micros.unsigned_abs());
+                let timestamp = if micros >= 0 {
+                    start + offset
+                } else {
+                    start.checked_sub(offset).unwrap_or(start)
+                };
+                let mut values = [0.0; 6];
+                for value in &mut values {
+                    *value = f32::from_le_bytes(take(file)?) as f64;
+                }
+                let [accel_x, accel_y, accel_z, gyro_x, gyro_y, gyro_z] = values;
+                frames.push(JoyconAxisData {
+                    timestamp,
+                    accel_x,
+                    accel_y,
+                    accel_z,
+                    gyro_x,
+                    gyro_y,
+                    gyro_z,
+                });
+            }
+            ChannelInfo::ImuData([frames[0], frames[1], frames[2]])
+        }
+        BATTERY => {
+            let battery = take_index(file, &BATTERIES)?;
+            let charge = f32::from_le_bytes(take(file)?);
+            ChannelInfo::Battery(battery, (!charge.is_nan()).then_some(charge))
+        }
+        BUTTONS => {
+            let [count] = take(file)?;
+            let held = (0..count)
+                .map(|_| take_index(file, &Button::ALL))
+                .collect::<io::Result<_>>()?;
+            ChannelInfo::Buttons(held)
+        }
+        DISCONNECTED => ChannelInfo::Disconnected,
+        kind => return Err(invalid(format!("Unknown record kind {kind}"))),
+    };
+    Ok(Some(Record {
+        time,
+        data: ChannelData::new(serial_number, info),
+    }))
+}
+
+fn take<const N: usize>(file: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn take_str(file: &mut impl Read) -> io::Result<String> {
+    let [len] = take(file)?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn take_index<T: Copy>(file: &mut impl Read, all: &[T]) -> io::Result<T> {
+    let [index] = take(file)?;
+    all.get(index as usize)
+        .copied()
+        .ok_or_else(|| invalid(format!("Unknown value {index}")))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Sends a recording to Wrangler with the timing the controllers sent it with. The records are
+/// read as they are due, so a long recording doesn't have to fit in memory.
+pub fn replay(path: PathBuf, tx: mpsc::Sender<ChannelData>) {
+    let start = Instant::now();
+    let mut file = match open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("[ERROR] Recorder - Could not read {}: {e}.", path.display());
+            return;
+        }
+    };
+    loop {
+        let record = match next_record(&mut file, start) {
+            Ok(Some(record)) => record,
+            Ok(None) => return,
+            Err(e) => {
+                println!("[ERROR] Recorder - Could not read {}: {e}.", path.display());
+                return;
+            }
+        };
+        thread::sleep((start + record.time).saturating_duration_since(Instant::now()));
+        if tx.send(record.data).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,1327 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeSet, VecDeque},
+        io,
+        net::SocketAddr,
+        rc::Rc,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use protocol::{
+        deku::{DekuContainerRead, DekuContainerWrite},
+        mock::MockServer,
+        PacketType, SensorStatus, ServerPacket, UserActionType,
+    };
+
+    use crate::{
+        joycon::{
+            clock::Clock,
+            imu::{JoyconAxisData, FRAME_PERIOD},
+            recorder,
+            transport::Transport,
+            Battery, ChannelData, ChannelInfo, Communication, DeviceStatus, JoyconDesign,
+            JoyconDesignType, ServerInfo, ServerStatus, Status,
+        },
+        settings::{self, Button, FilterKind, VqfParams, WranglerSettings},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(6);
+
+    struct Wrangler {
+        tx: mpsc::Sender<ChannelData>,
+        server_rx: mpsc::Receiver<Vec<ServerInfo>>,
+    }
+    impl Wrangler {
+        fn start(server: &MockServer) -> Self {
+            Self::start_all(&[server])
+        }
+        fn start_all(servers: &[&MockServer]) -> Self {
+            Self::start_with(WranglerSettings {
+                addresses: servers.iter().map(|s| s.address().to_string()).collect(),
+                ..WranglerSettings::new()
+            })
+        }
+        fn start_with(settings: WranglerSettings) -> Self {
+            let settings = settings::Handler::in_memory(settings);
+            let (tx, rx) = mpsc::channel();
+            let (status_tx, _) = mpsc::channel();
+            let (server_tx, server_rx) = mpsc::channel();
+            thread::spawn(move || Communication::start(rx, status_tx, server_tx, settings));
+            Self { tx, server_rx }
+        }
+        /// Every status of the servers up to the one where all of them have `status`.
+        fn wait_for_status(&self, status: ServerStatus) -> Vec<Vec<ServerStatus>> {
+            let deadline = Instant::now() + TIMEOUT;
+            let mut seen = vec![];
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match self.server_rx.recv_timeout(left) {
+                    Ok(servers) => {
+                        let statuses: Vec<_> = servers.iter().map(|s| s.status).collect();
+                        let done = statuses.iter().all(|s| *s == status);
+                        seen.push(statuses);
+                        if done {
+                            return seen;
+                        }
+                    }
+                    Err(_) => panic!("Timed out waiting for {status:?}, got {seen:?}"),
+                }
+            }
+        }
+        fn send(&self, info: ChannelInfo) {
+            self.tx
+                .send(ChannelData::new("test_0".into(), info))
+                .unwrap();
+        }
+        fn connect_controller(&self) {
+            self.send(ChannelInfo::Connected(JoyconDesign {
+                color: "#828282".into(),
+                design_type: JoyconDesignType::Left,
+            }));
+        }
+    }
+
+    thread_local! {
+        static NEXT_FRAME: Cell<Option<Instant>> = Cell::new(None);
+    }
+
+    fn frame() -> JoyconAxisData {
+        JoyconAxisData {
+            timestamp: Instant::now(),
+            accel_x: 0.0,
+            accel_y: -1.0,
+            accel_z: 0.0,
+            gyro_x: 0.0,
+            gyro_y: 0.0,
+            gyro_z: 0.02,
+        }
+    }
+
+    /// A report of three frames, 5 ms apart and after the frames of the previous report.
+    fn frames() -> [JoyconAxisData; 3] {
+        frames_after(Duration::ZERO)
+    }
+
+    /// A report that comes `gap` after the previous one should have.
+    fn frames_after(gap: Duration) -> [JoyconAxisData; 3] {
+        let start = NEXT_FRAME
+            .with(|next| next.get())
+            .unwrap_or_else(Instant::now)
+            + gap;
+        NEXT_FRAME.with(|next| next.set(Some(start + FRAME_PERIOD * 3)));
+        [0, 1, 2].map(|i| JoyconAxisData {
+            timestamp: start + FRAME_PERIOD * i,
+            ..frame()
+        })
+    }
+
+    #[test]
+    fn connects_to_server() {
+        let server = MockServer::start().unwrap();
+        let wrangler = Wrangler::start(&server);
+
+        assert_eq!(
+            wrangler.wait_for_status(ServerStatus::Connected),
+            [
+                [ServerStatus::Disconnected],
+                [ServerStatus::Unknown],
+                [ServerStatus::Connected]
+            ]
+        );
+        assert!(matches!(
+            server.packets().first(),
+            Some(PacketType::Handshake { .. })
+        ));
+    }
+
+    #[test]
+    fn registers_sensor() {
+        let server = MockServer::start().unwrap();
+        let wrangler = Wrangler::start(&server);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        wrangler.connect_controller();
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(
+                p,
+                PacketType::SensorInfo {
+                    sensor_id: 0,
+                    sensor_status: SensorStatus::Ok,
+                    ..
+                }
+            ))
+            .is_some());
+    }
+
+    #[test]
+    fn sends_rotation() {
+        let server = MockServer::start().unwrap();
+        let wrangler = Wrangler::start(&server);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        wrangler.connect_controller();
+        wrangler.send(ChannelInfo::ImuData(frames()));
+
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(
+                p,
+                PacketType::RotationData { sensor_id: 0, .. }
+            ))
+            .is_some());
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(
+                p,
+                PacketType::Acceleration {
+                    sensor_id: Some(0),
+                    ..
+                }
+            ))
+            .is_some());
+    }
+
+    #[test]
+    fn sends_rotation_in_bundles() {
+        let server = MockServer::start().unwrap();
+        server.set_bundles(true);
+        let wrangler = Wrangler::start(&server);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        wrangler.connect_controller();
+        // Give the feature flags time to arrive, a single byte like from the SlimeVR server
+        thread::sleep(Duration::from_millis(200));
+        wrangler.send(ChannelInfo::ImuData(frames()));
+
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(p, PacketType::Bundle { .. }))
+            .is_some());
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(
+                p,
+                PacketType::RotationData { sensor_id: 0, .. }
+            ))
+            .is_some());
+    }
+
+    #[test]
+    fn sends_rotation_unbundled_without_the_flag() {
+        let server = MockServer::start().unwrap();
+        let wrangler = Wrangler::start(&server);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        wrangler.connect_controller();
+        // Feature flags arrive with a single zero byte, which must not enable bundles
+        thread::sleep(Duration::from_millis(200));
+        wrangler.send(ChannelInfo::ImuData(frames()));
+
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(
+                p,
+                PacketType::RotationData { sensor_id: 0, .. }
+            ))
+            .is_some());
+        assert!(!server
+            .packets()
+            .iter()
+            .any(|p| matches!(p, PacketType::Bundle { .. })));
+    }
+
+    #[test]
+    fn reconnects_after_drop_out() {
+        let server = MockServer::start().unwrap();
+        let wrangler = Wrangler::start(&server);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        server.set_online(false);
+        wrangler.wait_for_status(ServerStatus::Disconnected);
+
+        server.set_online(true);
+        wrangler.wait_for_status(ServerStatus::Connected);
+    }
+
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+    impl FakeClock {
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    const SERVER: &str = "127.0.0.1:6969";
+
+    #[derive(Clone, Default)]
+    struct FakeTransport {
+        sent: Rc<RefCell<Vec<(Vec<u8>, SocketAddr)>>>,
+        incoming: Rc<RefCell<VecDeque<(Vec<u8>, SocketAddr)>>>,
+        /// Makes every send fail with this error.
+        fail: Rc<Cell<Option<io::ErrorKind>>>,
+        failed: Rc<Cell<u32>>,
+        /// Every transport opened from this one.
+        opened: Rc<RefCell<Vec<FakeTransport>>>,
+    }
+    impl FakeTransport {
+        fn push(&self, packet: ServerPacket) {
+            self.push_from(packet, SERVER.parse().unwrap());
+        }
+        fn push_from(&self, packet: ServerPacket, from: SocketAddr) {
+            self.push_bytes(packet.to_bytes().unwrap(), from);
+        }
+        fn push_bytes(&self, bytes: Vec<u8>, from: SocketAddr) {
+            self.incoming.borrow_mut().push_back((bytes, from));
+        }
+        fn take_sent(&self) -> Vec<PacketType> {
+            self.take_sent_to().into_iter().map(|(p, _)| p).collect()
+        }
+        fn take_sent_to(&self) -> Vec<(PacketType, SocketAddr)> {
+            self.sent
+                .take()
+                .iter()
+                .map(|(bytes, to)| (PacketType::from_bytes((bytes, 0)).unwrap().1, *to))
+                .collect()
+        }
+    }
+    impl Transport for FakeTransport {
+        fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize> {
+            if let Some(kind) = self.fail.get() {
+                self.failed.set(self.failed.get() + 1);
+                return Err(kind.into());
+            }
+            self.sent.borrow_mut().push((buf.to_vec(), address));
+            Ok(buf.len())
+        }
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let (data, from) = self
+                .incoming
+                .borrow_mut()
+                .pop_front()
+                .ok_or(io::ErrorKind::WouldBlock)?;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok((data.len(), from))
+        }
+        fn open(&self) -> io::Result<Box<dyn Transport>> {
+            let transport = FakeTransport {
+                sent: Rc::default(),
+                incoming: Rc::default(),
+                ..self.clone()
+            };
+            self.opened.borrow_mut().push(transport.clone());
+            Ok(Box::new(transport))
+        }
+    }
+
+    struct Harness {
+        comm: Communication,
+        settings: settings::Handler,
+        clock: FakeClock,
+        transport: FakeTransport,
+        tx: mpsc::Sender<ChannelData>,
+        status_rx: mpsc::Receiver<Vec<Status>>,
+        server_rx: mpsc::Receiver<Vec<ServerInfo>>,
+    }
+    impl Harness {
+        fn new() -> Self {
+            Self::with_settings(WranglerSettings::new())
+        }
+        fn with_settings(settings: WranglerSettings) -> Self {
+            let settings = settings::Handler::in_memory(settings);
+            let clock = FakeClock(Rc::new(Cell::new(Instant::now())));
+            let transport = FakeTransport::default();
+            let (tx, rx) = mpsc::channel();
+            let (status_tx, status_rx) = mpsc::channel();
+            let (server_tx, server_rx) = mpsc::channel();
+            let comm = Communication::new(
+                rx,
+                status_tx,
+                server_tx,
+                settings.clone(),
+                Box::new(clock.clone()),
+                Box::new(transport.clone()),
+            );
+            Self {
+                comm,
+                settings,
+                clock,
+                transport,
+                tx,
+                status_rx,
+                server_rx,
+            }
+        }
+        fn connect(&mut self) {
+            self.comm.step();
+            self.transport.push(ServerPacket::HandshakeResponse);
+            self.comm.step();
+            self.server_rx.try_iter().count();
+            self.transport.take_sent();
+        }
+        /// Status changes of the first server.
+        fn statuses(&self) -> Vec<ServerStatus> {
+            self.server_rx.try_iter().map(|s| s[0].status).collect()
+        }
+        fn send(&mut self, info: ChannelInfo) {
+            self.tx
+                .send(ChannelData::new("test_0".into(), info))
+                .unwrap();
+            self.comm.step();
+        }
+        fn connect_controller(&mut self) {
+            self.send(ChannelInfo::Connected(JoyconDesign {
+                color: "#828282".into(),
+                design_type: JoyconDesignType::Left,
+            }));
+        }
+        fn opened_transport(&self, index: usize) -> FakeTransport {
+            self.transport.opened.borrow()[index].clone()
+        }
+        fn device_status(&self) -> DeviceStatus {
+            self.last_status().status
+        }
+        fn last_status(&self) -> Status {
+            self.status_rx.try_iter().last().unwrap().remove(0)
+        }
+        fn press(&mut self, button: Button) {
+            self.send(ChannelInfo::Buttons(BTreeSet::from([button])));
+            self.send(ChannelInfo::Buttons(BTreeSet::new()));
+        }
+    }
+
+    #[test]
+    fn server_status_transitions() {
+        let mut h = Harness::new();
+
+        h.comm.step();
+        assert!(matches!(
+            h.transport.take_sent()[..],
+            [PacketType::Handshake { .. }]
+        ));
+
+        h.transport.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        assert_eq!(
+            h.statuses(),
+            [
+                ServerStatus::Disconnected,
+                ServerStatus::Unknown,
+                ServerStatus::Connected
+            ]
+        );
+        assert!(matches!(
+            h.transport.take_sent()[..],
+            [PacketType::FeatureFlags { .. }]
+        ));
+
+        // Pings keep the connection alive, and are echoed back
+        h.clock.advance(Duration::from_secs(2));
+        h.transport.push(ServerPacket::Ping { id: 7 });
+        h.comm.step();
+        assert_eq!(h.transport.take_sent()[0], PacketType::Ping { id: 7 });
+        h.clock.advance(Duration::from_secs(2));
+        h.comm.step();
+        assert!(h.statuses().is_empty());
+
+        h.clock.advance(Duration::from_secs(1));
+        h.comm.step();
+        assert_eq!(h.statuses(), [ServerStatus::Disconnected]);
+        h.transport.take_sent();
+
+        // Handshakes are retried every 3 seconds while not connected
+        h.comm.step();
+        assert!(matches!(
+            h.transport.take_sent()[..],
+            [PacketType::Handshake { .. }]
+        ));
+        h.clock.advance(Duration::from_secs(2));
+        h.comm.step();
+        assert!(h.transport.take_sent().is_empty());
+        h.clock.advance(Duration::from_secs(1));
+        h.comm.step();
+        assert!(matches!(
+            h.transport.take_sent()[..],
+            [PacketType::Handshake { .. }]
+        ));
+    }
+
+    #[test]
+    fn device_status_classification() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        assert_eq!(h.device_status(), DeviceStatus::NoIMU);
+
+        for _ in 0..60 {
+            h.clock.advance(Duration::from_millis(15));
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        assert_eq!(h.device_status(), DeviceStatus::Healthy);
+
+        for _ in 0..10 {
+            h.clock.advance(Duration::from_millis(100));
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        assert_eq!(h.device_status(), DeviceStatus::LaggyIMU);
+
+        h.clock.advance(Duration::from_secs(2));
+        h.comm.step();
+        assert_eq!(h.device_status(), DeviceStatus::NoIMU);
+
+        h.send(ChannelInfo::Disconnected);
+        assert_eq!(h.device_status(), DeviceStatus::Disconnected);
+    }
+
+    #[test]
+    fn reset_debounce() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.transport.take_sent();
+
+        let resets = |h: &Harness| {
+            h.transport
+                .take_sent()
+                .into_iter()
+                .filter(|p| {
+                    matches!(
+                        p,
+                        PacketType::UserAction {
+                            typ: UserActionType::ResetYaw,
+                            ..
+                        }
+                    )
+                })
+                .count()
+        };
+
+        h.press(Button::Up);
+        assert_eq!(resets(&h), 0, "Nothing is sent in the first 2 seconds");
+
+        h.clock.advance(Duration::from_secs(2));
+        h.press(Button::Up);
+        assert_eq!(resets(&h), 1);
+
+        h.clock.advance(Duration::from_secs(1));
+        h.press(Button::B);
+        assert_eq!(resets(&h), 0);
+
+        h.clock.advance(Duration::from_secs(1));
+        h.press(Button::B);
+        assert_eq!(resets(&h), 1);
+    }
+
+    #[test]
+    fn reports_sensor_status() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.send(ChannelInfo::ImuData(frames()));
+
+        let reported = |h: &Harness| -> Vec<SensorStatus> {
+            h.transport
+                .take_sent()
+                .into_iter()
+                .filter_map(|p| match p {
+                    PacketType::SensorInfo { sensor_status, .. } => Some(sensor_status),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(reported(&h), [SensorStatus::Ok]);
+
+        h.clock.advance(Duration::from_millis(1500));
+        h.comm.step();
+        assert_eq!(reported(&h), [SensorStatus::Error], "IMU data stopped");
+
+        h.send(ChannelInfo::Disconnected);
+        assert_eq!(reported(&h), [SensorStatus::Disconnected]);
+
+        h.connect_controller();
+        assert!(reported(&h).is_empty(), "Offline until IMU data arrives");
+        h.send(ChannelInfo::ImuData(frames()));
+        assert_eq!(reported(&h), [SensorStatus::Ok]);
+    }
+
+    #[test]
+    fn applies_keep_id_changes() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.tx.send(ChannelData::new(
+            "test_1".into(),
+            ChannelInfo::Connected(JoyconDesign {
+                color: "#828282".into(),
+                design_type: JoyconDesignType::Right,
+            }),
+        ))
+        .unwrap();
+        h.comm.step();
+        h.transport.take_sent();
+
+        let sensors = |h: &Harness| -> Vec<(u8, SensorStatus)> {
+            let mut sensors: Vec<_> = h
+                .transport
+                .take_sent()
+                .into_iter()
+                .filter_map(|p| match p {
+                    PacketType::SensorInfo {
+                        sensor_id,
+                        sensor_status,
+                        ..
+                    } => Some((sensor_id, sensor_status)),
+                    _ => None,
+                })
+                .collect();
+            sensors.sort_by_key(|(id, _)| *id);
+            sensors
+        };
+
+        // Turned on without a restart, the servers forget the old ids
+        h.settings.change(|ws| ws.keep_ids = true);
+        h.comm.step();
+        assert_eq!(
+            sensors(&h),
+            [
+                (0, SensorStatus::Disconnected),
+                (1, SensorStatus::Ok),
+                (2, SensorStatus::Ok)
+            ]
+        );
+        assert_eq!(h.settings.load().joycon["test_1"].keep_id, 2);
+
+        h.settings
+            .change(|ws| ws.joycon_keep_id_swap("test_0", "test_1"));
+        h.comm.step();
+        assert_eq!(sensors(&h), [(1, SensorStatus::Ok), (2, SensorStatus::Ok)]);
+        h.send(ChannelInfo::ImuData(frames()));
+        assert!(h
+            .transport
+            .take_sent()
+            .iter()
+            .any(|p| matches!(p, PacketType::RotationData { sensor_id: 2, .. })));
+
+        // Back to numbering in order, which keeps the swap
+        h.settings.change(|ws| ws.keep_ids = false);
+        h.comm.step();
+        assert_eq!(
+            sensors(&h),
+            [
+                (0, SensorStatus::Ok),
+                (1, SensorStatus::Ok),
+                (2, SensorStatus::Disconnected)
+            ]
+        );
+    }
+
+    #[test]
+    fn forgetting_a_connected_id_gives_a_new_one() {
+        let mut settings = WranglerSettings::new();
+        settings.keep_ids = true;
+        let mut h = Harness::with_settings(settings);
+        h.connect();
+        h.connect_controller();
+        assert_eq!(h.settings.load().joycon["test_0"].keep_id, 1);
+        h.transport.take_sent();
+
+        h.settings.change(|ws| ws.joycon_keep_id_forget("test_0"));
+        h.comm.step();
+        assert_eq!(h.settings.load().joycon["test_0"].keep_id, 2);
+        let sensors: Vec<_> = h
+            .transport
+            .take_sent()
+            .into_iter()
+            .filter_map(|p| match p {
+                PacketType::SensorInfo {
+                    sensor_id,
+                    sensor_status,
+                    ..
+                } => Some((sensor_id, sensor_status)),
+                _ => None,
+            })
+            .collect();
+        assert!(sensors.contains(&(1, SensorStatus::Disconnected)));
+        assert!(sensors.contains(&(2, SensorStatus::Ok)));
+
+        // The freed id goes to the next controller
+        h.tx.send(ChannelData::new(
+            "test_1".into(),
+            ChannelInfo::Connected(JoyconDesign {
+                color: "#828282".into(),
+                design_type: JoyconDesignType::Right,
+            }),
+        ))
+        .unwrap();
+        h.comm.step();
+        assert_eq!(h.settings.load().joycon["test_1"].keep_id, 1);
+    }
+
+    #[test]
+    fn calibrates_gyro_bias() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let bias = |h: &Harness| h.settings.load().joycon["test_0"].gyro_bias;
+
+        // Moving around
+        for i in 0..300 {
+            let mut frames = frames();
+            for frame in &mut frames {
+                frame.gyro_x = if i % 2 == 0 { 0.5 } else { -0.5 };
+            }
+            h.send(ChannelInfo::ImuData(frames));
+        }
+        assert_eq!(bias(&h), None);
+
+        // Lying on a table, the gyro only reads its bias
+        for _ in 0..250 {
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let [x, y, z] = bias(&h).unwrap();
+        assert!(x.abs() < 1e-9 && y.abs() < 1e-9 && (z - 0.02).abs() < 1e-9);
+        assert_eq!(h.settings.load().joycon_gyro_bias_get("test_0"), [x, y, z]);
+    }
+
+    #[test]
+    fn calibrates_gyro_bias_by_time() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let bias = |h: &Harness| h.settings.load().joycon["test_0"].gyro_bias;
+
+        // Half the reports are lost, which still makes 3 seconds after 100 reports
+        for _ in 0..100 {
+            h.send(ChannelInfo::ImuData(frames_after(FRAME_PERIOD * 3)));
+        }
+        assert_eq!(bias(&h), None);
+        h.send(ChannelInfo::ImuData(frames_after(FRAME_PERIOD * 3)));
+        assert!(bias(&h).is_some());
+    }
+
+    #[test]
+    fn turning_slowly_is_no_gyro_bias() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+
+        // On a turntable, steady but far more than a bias
+        for _ in 0..400 {
+            h.send(ChannelInfo::ImuData(frames().map(|frame| JoyconAxisData {
+                gyro_z: 0.1,
+                ..frame
+            })));
+        }
+        assert_eq!(h.settings.load().joycon["test_0"].gyro_bias, None);
+    }
+
+    #[test]
+    fn applies_filter_settings_live() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let mut vqf = VqfParams {
+            tau_acc: 0.5,
+            ..VqfParams::default()
+        };
+        h.settings
+            .change(|ws| ws.joycon_vqf_set("test_0".into(), vqf));
+        for _ in 0..100 {
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let before = h.last_status().rotation;
+
+        // The orientation is kept
+        vqf.tau_acc = 5.0;
+        vqf.motion_bias_estimation = false;
+        h.settings
+            .change(|ws| ws.joycon_vqf_set("test_0".into(), vqf));
+        h.send(ChannelInfo::ImuData(frames()));
+        let after = h.last_status().rotation;
+        for (b, a) in [
+            (before.0, after.0),
+            (before.1, after.1),
+            (before.2, after.2),
+        ] {
+            assert!((b - a).abs() < 1.0, "{before:?} {after:?}");
+        }
+    }
+
+    #[test]
+    fn switches_filter_live() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.settings
+            .change(|ws| ws.joycon_filter_set("test_0".into(), FilterKind::Madgwick));
+        for _ in 0..100 {
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let before = h.last_status().rotation;
+        assert_ne!(before, (0.0, 0.0, 0.0));
+
+        // The new filter starts where the old one was
+        h.settings
+            .change(|ws| ws.joycon_filter_set("test_0".into(), FilterKind::Mahony));
+        h.send(ChannelInfo::ImuData(frames()));
+        let after = h.last_status().rotation;
+        for (b, a) in [
+            (before.0, after.0),
+            (before.1, after.1),
+            (before.2, after.2),
+        ] {
+            assert!((b - a).abs() < 1.0, "{before:?} {after:?}");
+        }
+    }
+
+    #[test]
+    fn switches_to_vqf_keeping_the_heading() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.settings
+            .change(|ws| ws.joycon_filter_set("test_0".into(), FilterKind::Mahony));
+        let report = || {
+            frames().map(|frame| JoyconAxisData {
+                accel_y: 0.0,
+                accel_z: 1.0,
+                gyro_z: 0.5,
+                ..frame
+            })
+        };
+        for _ in 0..100 {
+            h.send(ChannelInfo::ImuData(report()));
+        }
+        let before = h.last_status().rotation;
+        assert!(before.2 > 30.0, "{before:?}");
+
+        h.settings
+            .change(|ws| ws.joycon_filter_set("test_0".into(), FilterKind::Vqf));
+        h.send(ChannelInfo::ImuData(report()));
+        let after = h.last_status().rotation;
+        for (b, a) in [
+            (before.0, after.0),
+            (before.1, after.1),
+            (before.2, after.2),
+        ] {
+            assert!((b - a).abs() < 1.0, "{before:?} {after:?}");
+        }
+    }
+
+    #[test]
+    fn integrates_real_time_between_frames() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.settings
+            .change(|ws| ws.joycon_filter_set("test_0".into(), FilterKind::Madgwick));
+        let report = |gap| {
+            frames_after(gap).map(|frame| JoyconAxisData {
+                accel_y: 0.0,
+                accel_z: 1.0,
+                gyro_z: 0.5,
+                ..frame
+            })
+        };
+        let yaw = |h: &Harness| h.last_status().rotation.2.to_radians();
+
+        // Every other report is lost, the last frame is 1180 ms after the first
+        for _ in 0..40 {
+            h.send(ChannelInfo::ImuData(report(FRAME_PERIOD * 3)));
+        }
+        let expected = 0.5 * 1.185;
+        assert!((yaw(&h) - expected).abs() < 0.01, "{}", yaw(&h));
+
+        // A gap only counts as one sample period
+        h.send(ChannelInfo::ImuData(report(Duration::from_secs(2))));
+        let expected = expected + 0.5 * 0.015;
+        assert!((yaw(&h) - expected).abs() < 0.01, "{}", yaw(&h));
+    }
+
+    #[test]
+    fn recording_is_written_while_it_runs() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let path =
+            std::env::temp_dir().join(format!("wrangler-{}-live.imurec", std::process::id()));
+        h.settings.change(|ws| ws.recording = Some(path.clone()));
+        h.send(ChannelInfo::ImuData(frames()));
+
+        // Read before the recording is stopped, like after Wrangler was killed
+        let records = recorder::read(&path, Instant::now()).unwrap();
+        h.settings.change(|ws| ws.recording = None);
+        h.comm.step();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[1].data.info, ChannelInfo::ImuData(_)));
+    }
+
+    #[test]
+    fn records_controller_data() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let path = std::env::temp_dir().join(format!("wrangler-{}.imurec", std::process::id()));
+        h.settings.change(|ws| ws.recording = Some(path.clone()));
+        let sent = frames();
+        h.send(ChannelInfo::ImuData(sent));
+        h.send(ChannelInfo::Battery(Battery::Low, Some(0.3)));
+        h.send(ChannelInfo::Buttons(BTreeSet::from([
+            Button::A,
+            Button::Zr,
+        ])));
+        h.send(ChannelInfo::Disconnected);
+        h.settings.change(|ws| ws.recording = None);
+        h.comm.step();
+
+        let records = recorder::read(&path, Instant::now()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(records.iter().all(|r| r.data.serial_number == "test_0"));
+        let infos: Vec<_> = records.into_iter().map(|r| r.data.info).collect();
+        assert_eq!(infos.len(), 5, "{infos:?}");
+        let ChannelInfo::Connected(design) = &infos[0] else {
+            panic!("{infos:?}")
+        };
+        let ChannelInfo::ImuData(frames) = &infos[1] else {
+            panic!("{infos:?}")
+        };
+        let ChannelInfo::Battery(battery, charge) = infos[2] else {
+            panic!("{infos:?}")
+        };
+        let ChannelInfo::Buttons(held) = &infos[3] else {
+            panic!("{infos:?}")
+        };
+        assert!(matches!(infos[4], ChannelInfo::Disconnected));
+        assert_eq!(design.color, "#828282");
+        assert_eq!(design.design_type, JoyconDesignType::Left);
+        for (frame, sent) in frames.iter().zip(sent) {
+            assert!((frame.gyro_z - sent.gyro_z).abs() < 1e-6);
+            assert!((frame.accel_y - sent.accel_y).abs() < 1e-6);
+        }
+        for w in frames.windows(2) {
+            let gap = w[1].timestamp - w[0].timestamp;
+            assert!((gap.as_secs_f64() - FRAME_PERIOD.as_secs_f64()).abs() < 2e-6);
+        }
+        assert_eq!((battery, charge), (Battery::Low, Some(0.3)));
+        assert_eq!(*held, BTreeSet::from([Button::A, Button::Zr]));
+    }
+
+    #[test]
+    fn replays_a_cut_off_recording() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        let path =
+            std::env::temp_dir().join(format!("wrangler-{}-replay.imurec", std::process::id()));
+        h.settings.change(|ws| ws.recording = Some(path.clone()));
+        h.send(ChannelInfo::ImuData(frames()));
+        h.send(ChannelInfo::Disconnected);
+        h.settings.change(|ws| ws.recording = None);
+        h.comm.step();
+
+        // Killed while writing the last record
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let (tx, rx) = mpsc::channel();
+        recorder::replay(path.clone(), tx);
+        std::fs::remove_file(&path).unwrap();
+        let infos: Vec<_> = rx.try_iter().map(|data| data.info).collect();
+        assert_eq!(infos.len(), 2, "{infos:?}");
+        assert!(matches!(infos[0], ChannelInfo::Connected(_)));
+        assert!(matches!(infos[1], ChannelInfo::ImuData(_)));
+    }
+
+    #[test]
+    fn discovers_server() {
+        let mut h = Harness::with_settings(WranglerSettings {
+            discover_server: true,
+            ..WranglerSettings::new()
+        });
+        h.connect_controller();
+        assert_eq!(
+            h.server_rx.try_iter().last().unwrap()[0]
+                .address
+                .to_string(),
+            SERVER
+        );
+
+        let destinations: Vec<_> = h
+            .transport
+            .take_sent_to()
+            .into_iter()
+            .filter(|(p, _)| matches!(p, PacketType::Handshake { .. }))
+            .map(|(_, to)| to.to_string())
+            .collect();
+        assert_eq!(destinations, [SERVER, "255.255.255.255:6969"]);
+
+        let server: SocketAddr = "192.168.1.20:6969".parse().unwrap();
+        h.transport
+            .push_from(ServerPacket::HandshakeResponse, server);
+        h.comm.step();
+        let info = h.server_rx.try_iter().last().unwrap().remove(0);
+        assert_eq!(info.address, server);
+        assert_eq!(info.status, ServerStatus::Connected);
+        assert!(info.discovered());
+        // The sensor is registered again on the new server
+        assert!(h
+            .transport
+            .take_sent_to()
+            .iter()
+            .any(|(p, to)| matches!(p, PacketType::SensorInfo { .. }) && *to == server));
+
+        h.transport.push_from(ServerPacket::Ping { id: 3 }, server);
+        h.comm.step();
+        assert_eq!(
+            h.transport.take_sent_to(),
+            [(PacketType::Ping { id: 3 }, server)]
+        );
+    }
+
+    #[test]
+    fn applies_address_change() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.transport.take_sent();
+
+        let server: SocketAddr = "192.168.1.30:6969".parse().unwrap();
+        h.settings
+            .change(|ws| ws.addresses = vec!["192.168.1.".into()]);
+        h.comm.step();
+        assert!(h.statuses().is_empty(), "Invalid addresses are ignored");
+
+        h.settings
+            .change(|ws| ws.addresses = vec![server.to_string()]);
+        h.comm.step();
+        assert_eq!(
+            h.server_rx.try_iter().collect::<Vec<_>>(),
+            [vec![ServerInfo {
+                serial_number: None,
+                configured: server,
+                address: server,
+                status: ServerStatus::Disconnected,
+                rtt: None,
+                send_errors: 0,
+            }]]
+        );
+        let sent = h.transport.take_sent_to();
+        assert!(matches!(sent[0], (PacketType::Handshake { .. }, to) if to == server));
+        assert!(matches!(
+            sent[1],
+            (PacketType::SensorInfo { sensor_id: 0, .. }, to) if to == server
+        ));
+
+        h.transport
+            .push_from(ServerPacket::HandshakeResponse, server);
+        h.comm.step();
+        assert_eq!(h.statuses().last(), Some(&ServerStatus::Connected));
+    }
+
+    #[test]
+    fn tracks_servers_independently() {
+        let other: SocketAddr = "192.168.1.40:6969".parse().unwrap();
+        let mut h = Harness::with_settings(WranglerSettings {
+            addresses: vec![SERVER.into(), other.to_string()],
+            ..WranglerSettings::new()
+        });
+        h.comm.step();
+        h.transport.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        let statuses: Vec<_> = h.server_rx.try_iter().last().unwrap()[..]
+            .iter()
+            .map(|s| s.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [ServerStatus::Connected, ServerStatus::Disconnected]
+        );
+
+        h.connect_controller();
+        h.send(ChannelInfo::ImuData(frames()));
+        let sent = h.transport.take_sent_to();
+        for to in [SERVER.parse().unwrap(), other] {
+            let ids: Vec<_> = sent
+                .iter()
+                .filter(|(_, t)| *t == to)
+                .filter_map(|(p, _)| match p {
+                    PacketType::SensorInfo { packet_id, .. }
+                    | PacketType::RotationData { packet_id, .. }
+                    | PacketType::Acceleration { packet_id, .. } => Some(*packet_id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(ids.len(), 3, "Both servers get the data");
+            assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+        }
+    }
+
+    #[test]
+    fn connects_over_ipv6() {
+        // Not every machine has IPv6
+        let Ok(server) = MockServer::bind("[::1]:0") else {
+            return;
+        };
+        let wrangler = Wrangler::start_with(WranglerSettings {
+            addresses: vec![server.address().to_string()],
+            bind_address: "[::]:0".into(),
+            ..WranglerSettings::new()
+        });
+        wrangler.wait_for_status(ServerStatus::Connected);
+        assert!(server
+            .wait_for(TIMEOUT, |p| matches!(p, PacketType::Handshake { .. }))
+            .is_some());
+    }
+
+    #[test]
+    fn sends_to_multiple_servers() {
+        let first = MockServer::start().unwrap();
+        let second = MockServer::start().unwrap();
+        let wrangler = Wrangler::start_all(&[&first, &second]);
+        wrangler.wait_for_status(ServerStatus::Connected);
+
+        wrangler.connect_controller();
+        wrangler.send(ChannelInfo::ImuData(frames()));
+
+        for server in [first, second] {
+            assert!(server
+                .wait_for(TIMEOUT, |p| matches!(
+                    p,
+                    PacketType::RotationData { sensor_id: 0, .. }
+                ))
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn ignores_unexpected_peers() {
+        let mut h = Harness::new();
+        let stranger: SocketAddr = "192.168.1.50:6969".parse().unwrap();
+        h.comm.step();
+        h.transport.take_sent();
+        h.statuses();
+
+        h.transport
+            .push_from(ServerPacket::HandshakeResponse, stranger);
+        h.transport
+            .push_from(ServerPacket::Ping { id: 1 }, stranger);
+        h.comm.step();
+        assert!(h.statuses().is_empty());
+        assert!(h.transport.take_sent().is_empty(), "Pings are not echoed");
+
+        // Invalid packets from the server are logged and dropped
+        h.transport
+            .push_bytes(vec![0, 0, 0, 10], SERVER.parse().unwrap());
+        h.transport
+            .push_bytes(vec![0, 0, 0, 99, 1, 2], SERVER.parse().unwrap());
+        h.comm.step();
+        assert_eq!(h.statuses(), [ServerStatus::Unknown]);
+        assert!(h.transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn measures_round_trip() {
+        let mut h = Harness::new();
+        h.comm.step();
+        h.transport.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        h.clock.advance(Duration::from_millis(20));
+        h.transport.push(ServerPacket::FeatureFlags {
+            packet_id: 0,
+            flags: Default::default(),
+        });
+        h.comm.step();
+        let info = h.server_rx.try_iter().last().unwrap().remove(0);
+        assert_eq!(info.rtt, Some(Duration::from_millis(20)));
+
+        // Measured again every 2 seconds
+        h.clock.advance(Duration::from_millis(1980));
+        h.transport.push(ServerPacket::Heartbeat);
+        h.comm.step();
+        assert!(h
+            .transport
+            .take_sent()
+            .iter()
+            .any(|p| matches!(p, PacketType::FeatureFlags { .. })));
+        h.clock.advance(Duration::from_millis(35));
+        h.transport.push(ServerPacket::FeatureFlags {
+            packet_id: 0,
+            flags: Default::default(),
+        });
+        h.comm.step();
+        let info = h.server_rx.try_iter().last().unwrap().remove(0);
+        assert_eq!(info.rtt, Some(Duration::from_millis(35)));
+    }
+
+    #[test]
+    fn measures_round_trip_with_server_flags() {
+        let mut h = Harness::new();
+        h.comm.step();
+        h.transport.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        h.clock.advance(Duration::from_millis(15));
+        // Reply as sent by the SlimeVR server, with a single byte of flags
+        h.transport.push_bytes(
+            vec![0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 1, 1],
+            SERVER.parse().unwrap(),
+        );
+        h.comm.step();
+        let info = h.server_rx.try_iter().last().unwrap().remove(0);
+        assert_eq!(info.rtt, Some(Duration::from_millis(15)));
+
+        // The flags were understood, so rotations go out in bundles
+        h.connect_controller();
+        h.transport.take_sent();
+        h.send(ChannelInfo::ImuData(frames()));
+        assert!(h
+            .transport
+            .take_sent()
+            .iter()
+            .any(|p| matches!(p, PacketType::Bundle { .. })));
+    }
+
+    #[test]
+    fn reports_link_statistics() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+
+        for _ in 0..100 {
+            h.clock.advance(Duration::from_millis(10));
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let status = h.last_status();
+        assert_eq!(status.imu_jitter, Duration::ZERO);
+        // The sensor info and a rotation and acceleration for every report
+        assert_eq!(status.packets_per_second, 201.0);
+
+        for i in 0..100 {
+            let interval = if i % 2 == 0 { 5 } else { 15 };
+            h.clock.advance(Duration::from_millis(interval));
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let jitter = h.last_status().imu_jitter.as_secs_f64() * 1000.0;
+        assert!((jitter - 5.0).abs() < 0.1, "{jitter}");
+    }
+
+    #[test]
+    fn recovers_from_send_failures() {
+        let mut h = Harness::new();
+        h.connect();
+        h.transport
+            .fail
+            .set(Some(io::ErrorKind::NetworkUnreachable));
+        h.connect_controller();
+        assert_eq!(
+            h.statuses(),
+            [ServerStatus::Error(io::ErrorKind::NetworkUnreachable)]
+        );
+
+        // Nothing is sent until the retry, which waits longer every time
+        h.send(ChannelInfo::ImuData(frames()));
+        assert_eq!(h.transport.failed.get(), 1);
+        h.clock.advance(Duration::from_secs(1));
+        h.comm.step();
+        assert_eq!(h.transport.failed.get(), 2);
+        h.clock.advance(Duration::from_secs(1));
+        h.comm.step();
+        assert_eq!(h.transport.failed.get(), 2);
+        h.clock.advance(Duration::from_secs(1));
+        h.comm.step();
+        assert_eq!(h.transport.failed.get(), 3);
+
+        h.transport.fail.set(None);
+        h.clock.advance(Duration::from_secs(4));
+        h.comm.step();
+        assert!(matches!(
+            h.transport.take_sent()[..],
+            [PacketType::Handshake { .. }, PacketType::SensorInfo { .. }]
+        ));
+        h.transport.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        assert_eq!(h.statuses().last(), Some(&ServerStatus::Connected));
+    }
+
+    #[test]
+    fn transient_send_errors_keep_the_connection() {
+        let mut h = Harness::new();
+        h.connect();
+        h.connect_controller();
+        h.transport.take_sent();
+
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted] {
+            h.transport.fail.set(Some(kind));
+            h.send(ChannelInfo::ImuData(frames()));
+        }
+        let info = h.server_rx.try_iter().last().unwrap().remove(0);
+        assert_eq!(info.status, ServerStatus::Connected);
+        assert_eq!(u64::from(h.transport.failed.get()), info.send_errors);
+        assert!(info.send_errors >= 2);
+
+        // The next packet goes out without waiting for a retry
+        h.transport.fail.set(None);
+        h.send(ChannelInfo::ImuData(frames()));
+        assert!(!h.transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn emulates_device_per_controller() {
+        let mut h = Harness::with_settings(WranglerSettings {
+            device_per_controller: true,
+            ..WranglerSettings::new()
+        });
+        // There is no device without a controller
+        h.comm.step();
+        assert!(h.transport.take_sent().is_empty());
+
+        h.connect_controller();
+        h.tx.send(ChannelData::new(
+            "test_1".into(),
+            ChannelInfo::Connected(JoyconDesign {
+                color: "#828282".into(),
+                design_type: JoyconDesignType::Right,
+            }),
+        ))
+        .unwrap();
+        h.comm.step();
+        h.comm.step();
+
+        // Each controller has its own socket and MAC address, and is sensor 0 of its device
+        let second = h.opened_transport(0);
+        let first_mac = h.settings.load().joycon["test_0"].emulated_mac.unwrap();
+        let second_mac = h.settings.load().joycon["test_1"].emulated_mac.unwrap();
+        assert_ne!(first_mac, second_mac);
+        for (transport, mac) in [(&h.transport, first_mac), (&second, second_mac)] {
+            let sent = transport.take_sent();
+            assert!(
+                matches!(
+                    sent[..],
+                    [
+                        PacketType::Handshake { mac_address, .. },
+                        PacketType::SensorInfo { sensor_id: 0, .. }
+                    ] if mac_address == mac
+                ),
+                "{sent:?}"
+            );
+        }
+
+        // The devices connect independently
+        second.push(ServerPacket::HandshakeResponse);
+        h.comm.step();
+        let servers = h.server_rx.try_iter().last().unwrap();
+        let statuses: Vec<_> = servers
+            .iter()
+            .map(|s| (s.serial_number.as_deref(), s.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (Some("test_0"), ServerStatus::Disconnected),
+                (Some("test_1"), ServerStatus::Connected)
+            ]
+        );
+    }
+}
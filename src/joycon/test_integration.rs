@@ -1,8 +1,12 @@
-use std::{sync::mpsc, thread, time::Duration};
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use super::{
     communication::{ChannelData, ChannelInfo},
-    imu::JoyconAxisData,
+    imu::{report_timestamps, JoyconAxisData},
     Battery, JoyconDesign, JoyconDesignType,
 };
 
@@ -18,6 +22,7 @@ fn spawn_test(tx: mpsc::Sender<ChannelData>, color: String, sn: String, z_change
 
     loop {
         let d = JoyconAxisData {
+            timestamp: Instant::now(),
             accel_x: 0.0,
             accel_y: -1.0,
             accel_z: 0.0,
@@ -27,13 +32,15 @@ fn spawn_test(tx: mpsc::Sender<ChannelData>, color: String, sn: String, z_change
         };
         tx.send(ChannelData {
             serial_number: sn.clone(),
-            info: ChannelInfo::ImuData([d, d, d]),
+            info: ChannelInfo::ImuData(
+                report_timestamps(d.timestamp).map(|timestamp| JoyconAxisData { timestamp, ..d }),
+            ),
         })
         .unwrap();
 
         tx.send(ChannelData {
             serial_number: sn.clone(),
-            info: ChannelInfo::Battery(Battery::Medium),
+            info: ChannelInfo::Battery(Battery::Medium, None),
         })
         .unwrap();
 
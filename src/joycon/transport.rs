@@ -0,0 +1,93 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Datagram transport of [`super::Communication`], so tests can run without a network.
+pub trait Transport {
+    fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize>;
+    /// Must not block, returns an error when nothing is waiting.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    /// Another transport on the same interface and a random port, for another emulated device.
+    fn open(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+/// A non-blocking UDP socket. When bound to an IPv6 address it still reaches IPv4 servers,
+/// through IPv4-mapped addresses.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    ipv6: bool,
+}
+impl UdpTransport {
+    /// Falls back to a random port if the port is taken.
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = bind_socket(address).or_else(|e| {
+            if address.port() == 0 {
+                return Err(e);
+            }
+            println!("[WARNING] Server - Could not bind to {address} ({e}), using a random port.");
+            bind_socket(SocketAddr::new(address.ip(), 0))
+        })?;
+        socket.set_nonblocking(true)?;
+        if address.is_ipv4() {
+            socket.set_broadcast(true).ok();
+        }
+        Ok(Self {
+            socket,
+            ipv6: address.is_ipv6(),
+        })
+    }
+}
+
+fn bind_socket(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if address.is_ipv6() {
+        // IPv6 only is the default on Windows, which would cut off IPv4 servers
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, buf: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let address = match address {
+            SocketAddr::V4(v4) if self.ipv6 => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            SocketAddr::V6(_) if !self.ipv6 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "IPv6 server with an IPv4 bind address",
+                ))
+            }
+            _ => address,
+        };
+        self.socket.send_to(buf, address)
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, from) = self.socket.recv_from(buf)?;
+        Ok((len, unmap(from)))
+    }
+    fn open(&self) -> io::Result<Box<dyn Transport>> {
+        let ip = self.socket.local_addr()?.ip();
+        Ok(Box::new(Self::bind(SocketAddr::new(ip, 0))?))
+    }
+}
+
+// Servers are configured with plain IPv4 addresses, so replies must be matched against those.
+fn unmap(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => v6
+            .ip()
+            .to_ipv4_mapped()
+            .map_or(address, |ip| SocketAddr::new(ip.into(), v6.port())),
+        SocketAddr::V4(_) => address,
+    }
+}
@@ -5,13 +5,20 @@ use crate::settings;
 #[cfg(target_os = "linux")]
 use super::linux_integration;
 use super::{
-    communication::ServerStatus, spawn_thread, test_integration::test_controllers, Communication,
-    Status,
+    communication::ServerInfo, recorder, spawn_thread, test_integration::test_controllers,
+    Communication, Status,
 };
 
+/// Value of a `--name value` command line argument.
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = env::args();
+    args.find(|a| a == name)?;
+    args.next()
+}
+
 pub struct Wrapper {
     status_rx: mpsc::Receiver<Vec<Status>>,
-    server_rx: mpsc::Receiver<ServerStatus>,
+    server_rx: mpsc::Receiver<Vec<ServerInfo>>,
 }
 impl Wrapper {
     pub fn new(settings: settings::Handler) -> Self {
@@ -19,6 +26,10 @@ impl Wrapper {
         let (server_tx, server_rx) = mpsc::channel();
         let (tx, rx) = mpsc::channel();
 
+        if let Some(path) = arg_value("--record") {
+            settings.change(|ws| ws.recording = Some(path.into()));
+        }
+
         {
             let settings = settings.clone();
             std::thread::spawn(move || {
@@ -33,6 +44,11 @@ impl Wrapper {
             }
         }
 
+        if let Some(path) = arg_value("--replay") {
+            let tx = tx.clone();
+            std::thread::spawn(move || recorder::replay(path.into(), tx));
+        }
+
         // evdev integration
         #[cfg(target_os = "linux")]
         {
@@ -51,7 +67,7 @@ impl Wrapper {
     pub fn poll_status(&self) -> Option<Vec<Status>> {
         self.status_rx.try_iter().last()
     }
-    pub fn poll_server(&self) -> Option<ServerStatus> {
+    pub fn poll_server(&self) -> Option<Vec<ServerInfo>> {
         self.server_rx.try_iter().last()
     }
 }
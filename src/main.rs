@@ -5,7 +5,7 @@ use iced::{
     theme::{self, Theme},
     time,
     widget::{
-        button, canvas, checkbox, container, horizontal_space, scrollable, slider, text,
+        button, canvas, checkbox, container, horizontal_space, pick_list, scrollable, slider, text,
         text_input, Column, Container, Row, Scrollable, Svg,
     },
     window, Alignment, Application, Color, Command, Element, Font, Length, Settings, Subscription,
@@ -13,15 +13,18 @@ use iced::{
 
 use circle::circle;
 use iced_aw::Grid;
-use joycon::{Battery, DeviceStatus, ServerStatus};
+use joycon::{Battery, DeviceStatus, ServerInfo, ServerStatus};
 use needle::Needle;
-use settings::WranglerSettings;
+use settings::{Action, Binding, Button, FilterKind, VqfParams, WranglerSettings};
 use std::{
+    collections::HashSet,
+    fmt::Display,
     io::{
         self,
         prelude::{Read, Write},
     },
     net::SocketAddr,
+    ops::RangeInclusive,
     time::{Duration, Instant},
 };
 mod joycon;
@@ -71,17 +74,39 @@ pub fn main() -> iced::Result {
 #[derive(Debug, Clone)]
 enum Message {
     SettingsPressed,
+    DiagnosticsPressed,
     Tick(Instant),
     Dot(Instant),
-    AddressChange(String),
+    AddressChange(usize, String),
+    AddressAdd,
+    AddressRemove(usize),
+    AddressPinPressed(SocketAddr, SocketAddr),
+    BindAddressChange(String),
     UpdateFound(Option<String>),
     UpdatePressed,
     BlacklistChecked(blacklist::BlacklistResult),
     BlacklistFixPressed,
     JoyconRotate(String, bool),
     JoyconScale(String, f64),
+    JoyconAdvancedToggled(String),
+    JoyconFilter(String, FilterKind),
+    JoyconVqf(String, VqfParams),
+    JoyconKeepIdForget(String),
+    JoyconKeepIdSwap(String, String),
+    JoyconKeepIdsCompact,
     SettingsResetToggled(bool),
+    SettingsFullResetToggled(bool),
+    SettingsMountingResetToggled(bool),
     SettingsIdsToggled(bool),
+    SettingsDiscoverToggled(bool),
+    SettingsDevicePerControllerToggled(bool),
+    SettingsBundleToggled(bool),
+    RecordingToggled,
+    BindingAdd,
+    BindingRemove(usize),
+    BindingButtons(usize, Button, Option<Button>),
+    BindingLongPress(usize, bool),
+    BindingAction(usize, Action),
 }
 
 #[derive(Default)]
@@ -90,8 +115,8 @@ struct MainState {
     joycon_boxes: JoyconBoxes,
     search_dots: usize,
     settings_show: bool,
-    server_connected: ServerStatus,
-    server_address: String,
+    diagnostics_show: bool,
+    servers: Vec<ServerInfo>,
 
     settings: settings::Handler,
     update_found: Option<String>,
@@ -106,7 +131,6 @@ impl Application for MainState {
     fn new(_: Self::Flags) -> (Self, Command<Self::Message>) {
         let mut new = Self::default();
         new.joycon = Some(joycon::Wrapper::new(new.settings.clone()));
-        new.server_address = format!("{}", new.settings.load().get_socket_address());
         (
             new,
             Command::batch(vec![
@@ -127,22 +151,42 @@ impl Application for MainState {
         match message {
             Message::SettingsPressed => {
                 self.settings_show = !self.settings_show;
+                self.diagnostics_show = false;
+            }
+            Message::DiagnosticsPressed => {
+                self.diagnostics_show = !self.diagnostics_show;
+                self.settings_show = false;
             }
             Message::Tick(_time) => {
                 if let Some(ref ji) = self.joycon {
                     if let Some(res) = ji.poll_status() {
                         self.joycon_boxes.statuses = res;
                     }
-                    if let Some(connected) = ji.poll_server() {
-                        self.server_connected = connected;
+                    if let Some(servers) = ji.poll_server() {
+                        self.servers = servers;
                     }
                 }
             }
             Message::Dot(_time) => {
                 self.search_dots = (self.search_dots + 1) % 4;
+                // Changes from the controllers are written here instead of from their thread
+                self.settings.save_unsaved();
+            }
+            Message::AddressChange(index, value) => {
+                self.settings.change(|ws| ws.address_set(index, value));
+            }
+            Message::AddressAdd => {
+                self.settings.change(|ws| ws.address_add());
             }
-            Message::AddressChange(value) => {
-                self.settings.change(|ws| ws.address = value);
+            Message::AddressRemove(index) => {
+                self.settings.change(|ws| ws.address_remove(index));
+            }
+            Message::AddressPinPressed(configured, address) => {
+                self.settings
+                    .change(|ws| ws.address_replace(configured, address));
+            }
+            Message::BindAddressChange(value) => {
+                self.settings.change(|ws| ws.bind_address = value);
             }
             Message::UpdateFound(version) => {
                 self.update_found = version;
@@ -168,12 +212,77 @@ impl Application for MainState {
                 self.settings
                     .change(|ws| ws.joycon_scale_set(serial_number, scale));
             }
+            Message::JoyconAdvancedToggled(serial_number) => {
+                let advanced = &mut self.joycon_boxes.advanced;
+                if !advanced.remove(&serial_number) {
+                    advanced.insert(serial_number);
+                }
+            }
+            Message::JoyconFilter(serial_number, filter) => {
+                self.settings
+                    .change(|ws| ws.joycon_filter_set(serial_number, filter));
+            }
+            Message::JoyconVqf(serial_number, vqf) => {
+                self.settings
+                    .change(|ws| ws.joycon_vqf_set(serial_number, vqf));
+            }
+            Message::JoyconKeepIdForget(serial_number) => {
+                self.settings
+                    .change(|ws| ws.joycon_keep_id_forget(&serial_number));
+            }
+            Message::JoyconKeepIdSwap(first, second) => {
+                self.settings
+                    .change(|ws| ws.joycon_keep_id_swap(&first, &second));
+            }
+            Message::JoyconKeepIdsCompact => {
+                self.settings.change(|ws| ws.joycon_keep_ids_compact());
+            }
             Message::SettingsResetToggled(new) => {
                 self.settings.change(|ws| ws.send_reset = new);
             }
+            Message::SettingsFullResetToggled(new) => {
+                self.settings.change(|ws| ws.send_full_reset = new);
+            }
+            Message::SettingsMountingResetToggled(new) => {
+                self.settings.change(|ws| ws.send_mounting_reset = new);
+            }
             Message::SettingsIdsToggled(new) => {
                 self.settings.change(|ws| ws.keep_ids = new);
             }
+            Message::SettingsDiscoverToggled(new) => {
+                self.settings.change(|ws| ws.discover_server = new);
+            }
+            Message::SettingsDevicePerControllerToggled(new) => {
+                self.settings.change(|ws| ws.device_per_controller = new);
+            }
+            Message::SettingsBundleToggled(new) => {
+                self.settings.change(|ws| ws.bundle_packets = new);
+            }
+            Message::RecordingToggled => {
+                let recording = match self.settings.load().recording {
+                    Some(_) => None,
+                    None => settings::recording_file_name(),
+                };
+                self.settings.change(|ws| ws.recording = recording);
+            }
+            Message::BindingAdd => {
+                self.settings.change(|ws| ws.binding_add());
+            }
+            Message::BindingRemove(index) => {
+                self.settings.change(|ws| ws.binding_remove(index));
+            }
+            Message::BindingButtons(index, first, second) => {
+                self.settings
+                    .change(|ws| ws.binding_buttons_set(index, first, second));
+            }
+            Message::BindingLongPress(index, long_press) => {
+                self.settings
+                    .change(|ws| ws.binding_long_press_set(index, long_press));
+            }
+            Message::BindingAction(index, action) => {
+                self.settings
+                    .change(|ws| ws.binding_action_set(index, action));
+            }
         }
         Command::none()
     }
@@ -194,7 +303,9 @@ impl Application for MainState {
 
         app.push(
             if self.settings_show {
-                container(self.settings_screen()).padding(20)
+                container(scrollable(self.settings_screen()).height(Length::Fill)).padding(20)
+            } else if self.diagnostics_show {
+                container(scrollable(self.diagnostics_screen()).height(Length::Fill)).padding(20)
             } else {
                 container(self.joycon_screen())
             }
@@ -202,11 +313,7 @@ impl Application for MainState {
             .height(Length::Fill)
             .style(style::container_darker as for<'r> fn(&'r _) -> _),
         )
-        .push(bottom_bar(
-            self.server_connected,
-            &".".repeat(self.search_dots),
-            &self.server_address,
-        ))
+        .push(bottom_bar(&self.servers, &".".repeat(self.search_dots)))
         .into()
     }
 }
@@ -230,42 +337,310 @@ impl MainState {
         );
         scrollable(list).height(Length::Fill)
     }
+    fn diagnostics_screen(&self) -> Column<'_, Message> {
+        let mut servers = Column::new()
+            .spacing(10)
+            .push(text("SlimeVR Servers").size(24));
+        for server in &self.servers {
+            let rtt = server
+                .rtt
+                .map_or("unknown".into(), |rtt| format!("{} ms", rtt.as_millis()));
+            let device = server
+                .serial_number
+                .as_ref()
+                .map_or(String::new(), |sn| format!(" from {sn}"));
+            servers = servers.push(text(format!(
+                "{}{device}: {}, round trip {rtt}, {} send errors",
+                server.address, server.status, server.send_errors
+            )));
+        }
+
+        let mut trackers = Column::new().spacing(10).push(text("Trackers").size(24));
+        for status in &self.joycon_boxes.statuses {
+            trackers = trackers.push(text(format!(
+                "{}: {}, {:.0} packets/s, IMU jitter {:.1} ms, {} packets sent",
+                status.serial_number,
+                status.status,
+                status.packets_per_second,
+                status.imu_jitter.as_secs_f64() * 1000.0,
+                status.packets_sent
+            )));
+        }
+
+        Column::new()
+            .spacing(20)
+            .push(text(
+                "High IMU jitter points at Bluetooth trouble, \
+                    a long round trip or send errors at network trouble.",
+            ))
+            .push(servers)
+            .push(trackers)
+            .push(recording(&self.settings.load()))
+    }
     fn settings_screen(&self) -> Column<'_, Message> {
         Column::new()
             .spacing(20)
-            .push(address(&self.settings.load().address))
+            .push(addresses(&self.settings.load()))
+            .push(bind_address(&self.settings.load().bind_address))
+            .push(checkbox(
+                "Find the SlimeVR Server on the local network if it is not at the addresses above.",
+                self.settings.load().discover_server,
+                Message::SettingsDiscoverToggled,
+            ))
             .push(checkbox(
-                "Send yaw reset command to SlimeVR Server after B or UP button press.",
+                "Send yaw reset command to SlimeVR Server when its button is pressed.",
                 self.settings.load().send_reset,
                 Message::SettingsResetToggled,
             ))
             .push(checkbox(
-                "Save mounting location on server. Requires SlimeVR Server v0.6.1 or newer. Restart Wrangler after changing this.",
+                "Send full reset command to SlimeVR Server when its button is pressed.",
+                self.settings.load().send_full_reset,
+                Message::SettingsFullResetToggled,
+            ))
+            .push(checkbox(
+                "Send mounting reset command to SlimeVR Server when its button is pressed.",
+                self.settings.load().send_mounting_reset,
+                Message::SettingsMountingResetToggled,
+            ))
+            .push(checkbox(
+                "Save mounting location on server. Requires SlimeVR Server v0.6.1 or newer.",
                 self.settings.load().keep_ids,
                 Message::SettingsIdsToggled,
             ))
+            .push(checkbox(
+                "Show every controller as its own tracker device on the SlimeVR Server, instead of as sensors of one device. Restart Wrangler after changing this.",
+                self.settings.load().device_per_controller,
+                Message::SettingsDevicePerControllerToggled,
+            ))
+            .push(checkbox(
+                "Bundle tracker data into fewer network packets, if the SlimeVR Server supports it.",
+                self.settings.load().bundle_packets,
+                Message::SettingsBundleToggled,
+            ))
+            .push(saved_controllers(&self.settings.load()))
+            .push(bindings(&self.settings.load().bindings))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ComboButton(Option<Button>);
+impl Display for ComboButton {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(button) => write!(f, "{button}"),
+            None => f.write_str("(none)"),
+        }
+    }
+}
+
+fn time_ago(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => "just now".into(),
+        s if s < 60 * 60 => format!("{} minutes ago", s / 60),
+        s if s < 24 * 60 * 60 => format!("{} hours ago", s / (60 * 60)),
+        s => format!("{} days ago", s / (24 * 60 * 60)),
+    }
+}
+
+fn saved_controllers<'a>(settings: &WranglerSettings) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(text(
+        "Saved controllers, the id is where the SlimeVR Server remembers the mounting location:",
+    ));
+    let mut serial_numbers: Vec<&String> = settings.joycon.keys().collect();
+    serial_numbers.sort_by_key(|sn| (settings.joycon[*sn].keep_id, *sn));
+    let now = settings::unix_time();
+    for sn in &serial_numbers {
+        let joycon = &settings.joycon[*sn];
+        let id = match joycon.keep_id {
+            0 => "no id".into(),
+            id => format!("id {id}"),
+        };
+        let seen = joycon.last_seen.map_or("never seen".into(), |t| {
+            format!("seen {}", time_ago(now.saturating_sub(t)))
+        });
+        let calibrated = if joycon.gyro_bias.is_some() {
+            ", gyro calibrated"
+        } else {
+            ""
+        };
+        let others: Vec<String> = serial_numbers
+            .iter()
+            .filter(|other| *other != sn)
+            .map(|other| other.to_string())
+            .collect();
+        let first = sn.to_string();
+        let mut forget =
+            button(text("Forget id")).style(theme::Button::Custom(Box::new(style::PrimaryButton)));
+        if joycon.keep_id != 0 {
+            forget = forget.on_press(Message::JoyconKeepIdForget(sn.to_string()));
+        }
+
+        let row = Row::new()
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .push(text(format!(
+                "{sn}: {id}, rotated {}°, {seen}{calibrated}",
+                joycon.rotation
+            )))
+            .push(
+                pick_list(others, None, move |second| {
+                    Message::JoyconKeepIdSwap(first.clone(), second)
+                })
+                .placeholder("Swap id with"),
+            )
+            .push(forget);
+        allc = allc.push(row);
     }
+    allc.push(
+        button(text("Compact ids"))
+            .on_press(Message::JoyconKeepIdsCompact)
+            .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+    )
 }
 
-fn address<'a>(input_value: &str) -> Column<'a, Message> {
-    let address = text_input("127.0.0.1:6969", input_value)
-        .on_input(Message::AddressChange)
-        .width(Length::Fixed(300.0))
-        .padding(10);
+fn bindings<'a>(bindings: &[Binding]) -> Column<'a, Message> {
+    let mut allc = Column::new().spacing(10).push(text("Controller buttons:"));
+    for (i, binding) in bindings.iter().enumerate() {
+        let mut buttons = binding.buttons.iter().copied();
+        let first = buttons.next().unwrap_or(Button::Up);
+        let second = buttons.next();
 
-    let address_row = Row::new()
+        let second_options: Vec<_> = std::iter::once(ComboButton(None))
+            .chain(
+                Button::ALL
+                    .into_iter()
+                    .filter(|b| *b != first)
+                    .map(|b| ComboButton(Some(b))),
+            )
+            .collect();
+
+        let row = Row::new()
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .push(pick_list(Button::ALL.to_vec(), Some(first), move |b| {
+                Message::BindingButtons(i, b, second.filter(|s| *s != b))
+            }))
+            .push(text("+"))
+            .push(pick_list(
+                second_options,
+                Some(ComboButton(second)),
+                move |b| Message::BindingButtons(i, first, b.0),
+            ))
+            .push(checkbox("Long press", binding.long_press, move |l| {
+                Message::BindingLongPress(i, l)
+            }))
+            .push(text("="))
+            .push(pick_list(
+                Action::ALL.to_vec(),
+                Some(binding.action),
+                move |a| Message::BindingAction(i, a),
+            ))
+            .push(
+                button(text("Remove"))
+                    .on_press(Message::BindingRemove(i))
+                    .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+            );
+        allc = allc.push(row);
+    }
+    allc.push(
+        button(text("Add button"))
+            .on_press(Message::BindingAdd)
+            .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+    )
+}
+
+fn addresses<'a>(settings: &WranglerSettings) -> Column<'a, Message> {
+    let addresses = &settings.addresses;
+    let bind_ipv6 = settings.get_bind_address().is_ipv6();
+    let mut allc = Column::new()
         .spacing(10)
-        .align_items(Alignment::Center)
-        .push("SlimeVR Server address:")
-        .push(address)
-        .push("Restart Wrangler after changing this.");
-    let mut allc = Column::new().push(address_row).spacing(10);
+        .push(text("SlimeVR Server addresses:"));
+    for (i, input_value) in addresses.iter().enumerate() {
+        let address = text_input("127.0.0.1:6969", input_value)
+            .on_input(move |a| Message::AddressChange(i, a))
+            .width(Length::Fixed(300.0))
+            .padding(10);
+        let mut remove =
+            button(text("Remove")).style(theme::Button::Custom(Box::new(style::PrimaryButton)));
+        if addresses.len() > 1 {
+            remove = remove.on_press(Message::AddressRemove(i));
+        }
 
+        let address_row = Row::new()
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .push(address)
+            .push(remove);
+        allc = allc.push(address_row);
+
+        match input_value.parse::<SocketAddr>() {
+            Err(_) => {
+                allc = allc.push(
+                    container(text(
+                        "Address is not a valid ip with port number! Using the last valid address until it is fixed.",
+                    ))
+                    .style(style::text_yellow as for<'r> fn(&'r _) -> _),
+                );
+            }
+            Ok(address) if address.is_ipv6() && !bind_ipv6 => {
+                allc = allc.push(
+                    container(text(
+                        "IPv6 servers need an IPv6 local address, like [::]:47589.",
+                    ))
+                    .style(style::text_yellow as for<'r> fn(&'r _) -> _),
+                );
+            }
+            Ok(_) => {}
+        }
+    }
+    allc.push(
+        button(text("Add server"))
+            .on_press(Message::AddressAdd)
+            .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+    )
+}
+fn recording<'a>(settings: &WranglerSettings) -> Column<'a, Message> {
+    let (status, label) = match &settings.recording {
+        Some(path) => (
+            format!("Recording the controller data to {}", path.display()),
+            "Stop recording",
+        ),
+        None => (
+            "Record everything the controllers send, to find out why a tracker misbehaves. \
+                Start Wrangler with --replay <file> to play a recording back."
+                .into(),
+            "Start recording",
+        ),
+    };
+    Column::new()
+        .spacing(10)
+        .push(text("Recording").size(24))
+        .push(text(status))
+        .push(
+            button(text(label))
+                .on_press(Message::RecordingToggled)
+                .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+        )
+}
+
+fn bind_address<'a>(input_value: &str) -> Column<'a, Message> {
+    let mut allc = Column::new()
+        .spacing(10)
+        .push(text(
+            "Local address and port to send from. Restart Wrangler after changing this.",
+        ))
+        .push(
+            text_input(settings::DEFAULT_BIND_ADDR, input_value)
+                .on_input(Message::BindAddressChange)
+                .width(Length::Fixed(300.0))
+                .padding(10),
+        );
     if input_value.parse::<SocketAddr>().is_err() {
         allc = allc.push(
-            container(text(
-                "Address is not a valid ip with port number! Using default instead (127.0.0.1:6969).",
-            ))
+            container(text(format!(
+                "Address is not a valid ip with port number! Using {} until it is fixed.",
+                settings::DEFAULT_BIND_ADDR
+            )))
             .style(style::text_yellow as for<'r> fn(&'r _) -> _),
         );
     }
@@ -286,11 +661,16 @@ fn top_bar<'a>(update: Option<String>) -> Container<'a, Message> {
             .push(update_btn);
     }
 
+    let diagnostics = button(text("Diagnostics"))
+        .style(theme::Button::Custom(Box::new(style::PrimaryButton)))
+        .on_press(Message::DiagnosticsPressed);
     let settings = button(text("Settings"))
         .style(theme::Button::Custom(Box::new(style::PrimaryButton)))
         .on_press(Message::SettingsPressed);
     top_column = top_column
         .push(horizontal_space(Length::Fill))
+        .push(diagnostics)
+        .push(horizontal_space(Length::Fixed(10.0)))
         .push(settings);
 
     container(top_column)
@@ -317,25 +697,39 @@ fn blacklist_bar<'a>(result: &blacklist::BlacklistResult) -> Container<'a, Messa
         .style(style::container_info as for<'r> fn(&'r _) -> _)
 }
 
-fn bottom_bar<'a>(
-    connected: ServerStatus,
-    search_dots: &String,
-    address: &String,
-) -> Container<'a, Message> {
-    let status = Row::new()
-        .push(text("Connection to SlimeVR Server: "))
-        .push(container(text(format!("{connected:?}"))).style(
-            if connected == ServerStatus::Connected {
-                style::text_green
+fn bottom_bar<'a>(servers: &[ServerInfo], search_dots: &str) -> Container<'a, Message> {
+    let mut status = Column::new().spacing(10);
+    for server in servers {
+        let connected = server.status;
+        let address = server.address;
+        let label = match &server.serial_number {
+            Some(sn) => format!("Connection of {sn} to SlimeVR Server: "),
+            None => "Connection to SlimeVR Server: ".into(),
+        };
+        let mut row = Row::new()
+            .align_items(Alignment::Center)
+            .push(text(label))
+            .push(container(text(format!("{connected}"))).style(
+                if connected == ServerStatus::Connected {
+                    style::text_green
+                } else {
+                    style::text_yellow
+                },
+            ))
+            .push(text(if connected == ServerStatus::Connected {
+                format!(" to {address}.")
             } else {
-                style::text_yellow
-            },
-        ))
-        .push(text(if connected == ServerStatus::Connected {
-            format!(" to {address}.")
-        } else {
-            format!(". Trying to connect to {address}{search_dots}")
-        }));
+                format!(". Trying to connect to {address}{search_dots}")
+            }));
+        if connected == ServerStatus::Connected && server.discovered() {
+            row = row.push(horizontal_space(Length::Fill)).push(
+                button(text("Save address"))
+                    .style(theme::Button::Custom(Box::new(style::PrimaryButton)))
+                    .on_press(Message::AddressPinPressed(server.configured, address)),
+            );
+        }
+        status = status.push(row);
+    }
     container(status)
         .width(Length::Fill)
         .padding(20)
@@ -347,6 +741,8 @@ struct JoyconBoxes {
     pub statuses: Vec<joycon::Status>,
     svg_handler: joycon::Svg,
     needles: Vec<Needle>,
+    /// Controllers with the advanced section open.
+    advanced: HashSet<String>,
 }
 
 impl Default for JoyconBoxes {
@@ -355,6 +751,7 @@ impl Default for JoyconBoxes {
             statuses: vec![],
             svg_handler: joycon::Svg::new(),
             needles: (0..360).map(Needle::new).collect(),
+            advanced: HashSet::new(),
         }
     }
 }
@@ -364,14 +761,25 @@ impl JoyconBoxes {
         self.statuses
             .iter()
             .map(|status| {
+                let sn = &status.serial_number;
+                let advanced = self
+                    .advanced
+                    .contains(sn)
+                    .then(|| (settings.joycon_filter_get(sn), settings.joycon_vqf_get(sn)));
+                let height = match advanced {
+                    None => 370.0,
+                    Some((FilterKind::Vqf, _)) => 660.0,
+                    Some(_) => 480.0,
+                };
                 container(single_box_view(
                     status,
                     &self.svg_handler,
                     &self.needles,
                     settings.joycon_scale_get(&status.serial_number),
                     settings.joycon_rotation_get(&status.serial_number),
+                    advanced,
                 ))
-                .height(Length::Fixed(335.0))
+                .height(Length::Fixed(height))
                 .width(Length::Fixed(300.0))
                 .padding(10)
                 .style(style::item_normal as for<'r> fn(&'r _) -> _)
@@ -386,6 +794,7 @@ fn single_box_view<'a>(
     needles: &'a [Needle],
     scale: f64,
     mount_rot: i32,
+    advanced: Option<(FilterKind, VqfParams)>,
 ) -> Column<'a, Message> {
     let sn = status.serial_number.clone();
 
@@ -464,11 +873,12 @@ fn single_box_view<'a>(
         DeviceStatus::Healthy => style::text_green,
     });
 
+    let scale_sn = sn.clone();
     let bottom = Column::new()
         .spacing(10)
         .push(
             slider(0.8..=1.2, scale, move |c| {
-                Message::JoyconScale(sn.clone(), c)
+                Message::JoyconScale(scale_sn.clone(), c)
             })
             .step(0.001),
         )
@@ -480,7 +890,139 @@ fn single_box_view<'a>(
             .size(14),
         )
         .push(Row::new().push(text("Battery level: ")).push(battery_text))
-        .push(Row::new().push(text("Status: ")).push(status_text));
+        .push(
+            Row::new()
+                .push(text("Status: "))
+                .push(status_text)
+                .push(
+                    text(if status.paused {
+                        format!(" (paused, {} packets sent)", status.packets_sent)
+                    } else {
+                        format!(" ({} packets sent)", status.packets_sent)
+                    })
+                    .size(14),
+                ),
+        )
+        .push(
+            button(text(if advanced.is_some() {
+                "Hide advanced"
+            } else {
+                "Advanced"
+            }))
+            .on_press(Message::JoyconAdvancedToggled(sn.clone()))
+            .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+        );
 
-    Column::new().spacing(10).push(top).push(bottom)
+    let mut column = Column::new().spacing(10).push(top).push(bottom);
+    if let Some((filter, vqf)) = advanced {
+        column = column.push(filter_settings(sn, filter, vqf));
+    }
+    column
+}
+
+fn labeled_slider<'a>(
+    label: String,
+    range: RangeInclusive<f64>,
+    value: f64,
+    step: f64,
+    on_change: impl Fn(f64) -> Message + 'a,
+) -> Column<'a, Message> {
+    Column::new()
+        .spacing(5)
+        .push(text(label).size(14))
+        .push(slider(range, value, on_change).step(step))
+}
+
+/// Choice and tuning of the orientation filter, changes apply right away.
+fn filter_settings<'a>(sn: String, filter: FilterKind, vqf: VqfParams) -> Column<'a, Message> {
+    let filter_sn = sn.clone();
+    let filter_list = Row::new()
+        .spacing(10)
+        .align_items(Alignment::Center)
+        .push(text("Filter"))
+        .push(pick_list(
+            FilterKind::ALL.to_vec(),
+            Some(filter),
+            move |f| Message::JoyconFilter(filter_sn.clone(), f),
+        ));
+    let message = move |vqf| Message::JoyconVqf(sn.clone(), vqf);
+    let m = message.clone();
+    let sample_period = labeled_slider(
+        format!("Sample period: {:.1} ms", vqf.sample_period * 1000.0),
+        0.002..=0.02,
+        vqf.sample_period,
+        0.0005,
+        move |sample_period| {
+            m(VqfParams {
+                sample_period,
+                ..vqf
+            })
+        },
+    );
+    let m = message.clone();
+    let tau_acc = labeled_slider(
+        format!("Accelerometer time constant: {:.1} s", vqf.tau_acc),
+        0.5..=10.0,
+        vqf.tau_acc,
+        0.1,
+        move |tau_acc| m(VqfParams { tau_acc, ..vqf }),
+    );
+    let m = message.clone();
+    let rest_gyro = labeled_slider(
+        format!("Rest threshold gyro: {:.1} °/s", vqf.rest_threshold_gyro),
+        0.5..=10.0,
+        vqf.rest_threshold_gyro,
+        0.1,
+        move |rest_threshold_gyro| {
+            m(VqfParams {
+                rest_threshold_gyro,
+                ..vqf
+            })
+        },
+    );
+    let m = message.clone();
+    let rest_accel = labeled_slider(
+        format!(
+            "Rest threshold accelerometer: {:.2} g",
+            vqf.rest_threshold_accel
+        ),
+        0.05..=2.0,
+        vqf.rest_threshold_accel,
+        0.05,
+        move |rest_threshold_accel| {
+            m(VqfParams {
+                rest_threshold_accel,
+                ..vqf
+            })
+        },
+    );
+    let m = message.clone();
+    let motion_bias = checkbox(
+        "Estimate gyro bias while moving",
+        vqf.motion_bias_estimation,
+        move |motion_bias_estimation| {
+            m(VqfParams {
+                motion_bias_estimation,
+                ..vqf
+            })
+        },
+    );
+
+    let mut column = Column::new()
+        .spacing(10)
+        .push(filter_list)
+        .push(sample_period);
+    // The other filters only use the sample period
+    if filter == FilterKind::Vqf {
+        column = column
+            .push(tau_acc)
+            .push(rest_gyro)
+            .push(rest_accel)
+            .push(motion_bias);
+    }
+    column.push(
+        button(text("Default filter settings"))
+            .on_press(message(VqfParams::default()))
+            .style(theme::Button::Custom(Box::new(style::PrimaryButton))),
+    )
 }
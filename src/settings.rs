@@ -1,15 +1,32 @@
 use std::{
-    collections::HashMap, fs, fs::File, io::BufReader, net::SocketAddr, path::PathBuf, sync::Arc,
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    fs,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use arc_swap::{ArcSwap, Guard};
 use directories::ProjectDirs;
+use itertools::Itertools;
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 fn file_name() -> Option<PathBuf> {
     ProjectDirs::from("", "", "SlimeVR Wrangler").map(|pd| pd.config_dir().join("config.json"))
 }
+/// A new file for recording the controller data to.
+pub fn recording_file_name() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "SlimeVR Wrangler").map(|pd| {
+        pd.data_dir()
+            .join("recordings")
+            .join(format!("{}.imurec", unix_time()))
+    })
+}
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Joycon {
     #[serde(default)]
@@ -18,6 +35,69 @@ pub struct Joycon {
     pub gyro_scale_factor: f64,
     #[serde(default)]
     pub keep_id: u8,
+    /// MAC address of the controller when it is emulated as its own device.
+    #[serde(default)]
+    pub emulated_mac: Option<[u8; 6]>,
+    /// Unix time the controller last connected or disconnected.
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    /// Gyro bias in radians/s, measured while the controller was lying still.
+    #[serde(default)]
+    pub gyro_bias: Option<[f64; 3]>,
+    #[serde(default)]
+    pub filter: FilterKind,
+    #[serde(default)]
+    pub vqf: VqfParams,
+}
+
+/// Sensor fusion algorithm that turns the IMU data into an orientation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    #[default]
+    Vqf,
+    Madgwick,
+    Mahony,
+}
+impl FilterKind {
+    pub const ALL: [FilterKind; 3] = [FilterKind::Vqf, FilterKind::Madgwick, FilterKind::Mahony];
+}
+impl Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FilterKind::Vqf => "VQF",
+            FilterKind::Madgwick => "Madgwick",
+            FilterKind::Mahony => "Mahony",
+        })
+    }
+}
+
+/// Tuning of the VQF orientation filter. Every filter uses the sample period for the first frame
+/// and after gaps in the data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct VqfParams {
+    /// Time between IMU frames in seconds.
+    pub sample_period: f64,
+    /// Time constant of the accelerometer correction in seconds, lower trusts the accelerometer
+    /// more.
+    pub tau_acc: f64,
+    /// Also estimate the gyro bias while the controller moves, not only at rest.
+    pub motion_bias_estimation: bool,
+    /// Rest detection threshold of the gyro, in degrees/s.
+    pub rest_threshold_gyro: f64,
+    /// Rest detection threshold of the accelerometer, in g as the controllers report it.
+    pub rest_threshold_accel: f64,
+}
+impl Default for VqfParams {
+    fn default() -> Self {
+        VqfParams {
+            sample_period: 0.005,
+            tau_acc: 3.0,
+            motion_bias_estimation: true,
+            rest_threshold_gyro: 2.0,
+            rest_threshold_accel: 0.5,
+        }
+    }
 }
 fn return_f64_one() -> f64 {
     1.0
@@ -29,21 +109,205 @@ impl Default for Joycon {
             rotation: 0,
             gyro_scale_factor: 1.0,
             keep_id: 0,
+            emulated_mac: None,
+            last_seen: None,
+            gyro_bias: None,
+            filter: FilterKind::default(),
+            vqf: VqfParams::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    X,
+    Y,
+    L,
+    R,
+    Zl,
+    Zr,
+    Sl,
+    Sr,
+    Minus,
+    Plus,
+    Home,
+    Capture,
+    LStick,
+    RStick,
+}
+impl Button {
+    pub const ALL: [Button; 20] = [
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+        Button::A,
+        Button::B,
+        Button::X,
+        Button::Y,
+        Button::L,
+        Button::R,
+        Button::Zl,
+        Button::Zr,
+        Button::Sl,
+        Button::Sr,
+        Button::Minus,
+        Button::Plus,
+        Button::Home,
+        Button::Capture,
+        Button::LStick,
+        Button::RStick,
+    ];
+}
+impl Display for Button {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Button::Up => "UP",
+            Button::Down => "DOWN",
+            Button::Left => "LEFT",
+            Button::Right => "RIGHT",
+            Button::A => "A",
+            Button::B => "B",
+            Button::X => "X",
+            Button::Y => "Y",
+            Button::L => "L",
+            Button::R => "R",
+            Button::Zl => "ZL",
+            Button::Zr => "ZR",
+            Button::Sl => "SL",
+            Button::Sr => "SR",
+            Button::Minus => "-",
+            Button::Plus => "+",
+            Button::Home => "HOME",
+            Button::Capture => "CAPTURE",
+            Button::LStick => "L STICK",
+            Button::RStick => "R STICK",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetKind {
+    Yaw,
+    Full,
+    Mounting,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Reset(ResetKind),
+    /// Stop sending data for the controller, until pressed again.
+    PauseTracker,
+    /// Reset the orientation of the controller in Wrangler, without telling the server.
+    ReZero,
+}
+impl Action {
+    pub const ALL: [Action; 5] = [
+        Action::Reset(ResetKind::Yaw),
+        Action::Reset(ResetKind::Full),
+        Action::Reset(ResetKind::Mounting),
+        Action::PauseTracker,
+        Action::ReZero,
+    ];
+}
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Reset(ResetKind::Yaw) => "Yaw reset",
+            Action::Reset(ResetKind::Full) => "Full reset",
+            Action::Reset(ResetKind::Mounting) => "Mounting reset",
+            Action::PauseTracker => "Pause tracker",
+            Action::ReZero => "Re-zero in Wrangler",
+        })
+    }
+}
+
+/// Buttons that are held down together to trigger an action.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub buttons: BTreeSet<Button>,
+    #[serde(default)]
+    pub long_press: bool,
+    pub action: Action,
+}
+impl Binding {
+    fn new(buttons: &[Button], action: Action) -> Self {
+        Self {
+            buttons: buttons.iter().copied().collect(),
+            long_press: false,
+            action,
         }
     }
 }
+fn return_bindings() -> Vec<Binding> {
+    vec![
+        Binding::new(&[Button::Up], Action::Reset(ResetKind::Yaw)),
+        Binding::new(&[Button::B], Action::Reset(ResetKind::Yaw)),
+        Binding::new(&[Button::Right], Action::Reset(ResetKind::Full)),
+        Binding::new(&[Button::A], Action::Reset(ResetKind::Full)),
+        Binding::new(&[Button::Down], Action::Reset(ResetKind::Mounting)),
+        Binding::new(&[Button::X], Action::Reset(ResetKind::Mounting)),
+    ]
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct WranglerSettings {
-    pub address: String,
+    /// Every server gets the same tracker data.
+    #[serde(alias = "address", deserialize_with = "deserialize_addresses")]
+    pub addresses: Vec<String>,
+    /// Broadcast the handshake and use whichever server on the local network answers.
+    #[serde(default = "return_false")]
+    pub discover_server: bool,
+    /// Local address and port to send from. Use an IPv6 address like `[::]:47589` to reach
+    /// IPv6 servers, IPv4 servers are still reached through it.
+    #[serde(default = "return_bind_address")]
+    pub bind_address: String,
     #[serde(default)]
     pub joycon: HashMap<String, Joycon>,
     #[serde(default = "return_true")]
     pub send_reset: bool,
+    #[serde(default = "return_false")]
+    pub send_full_reset: bool,
+    #[serde(default = "return_false")]
+    pub send_mounting_reset: bool,
     #[serde(default = "return_mac")]
     pub emulated_mac: [u8; 6],
     #[serde(default = "return_false")]
     pub keep_ids: bool,
+    /// Every controller shows up as its own tracker device, instead of as one sensor of a single
+    /// device.
+    #[serde(default = "return_false")]
+    pub device_per_controller: bool,
+    #[serde(default = "return_true")]
+    pub bundle_packets: bool,
+    #[serde(default = "return_bindings")]
+    pub bindings: Vec<Binding>,
+    /// Everything the controllers send is recorded to this file while it is set. Recordings
+    /// don't continue after a restart.
+    #[serde(skip)]
+    pub recording: Option<PathBuf>,
+}
+
+// Older versions only had a single address
+fn deserialize_addresses<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Addresses {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Addresses::deserialize(deserializer)? {
+        Addresses::One(address) => vec![address],
+        Addresses::Many(addresses) => addresses,
+    })
 }
 
 fn return_true() -> bool {
@@ -52,14 +316,42 @@ fn return_true() -> bool {
 fn return_false() -> bool {
     false
 }
+fn return_bind_address() -> String {
+    DEFAULT_BIND_ADDR.into()
+}
 fn return_mac() -> [u8; 6] {
     let mut r = rand::thread_rng();
     [0x00, 0x0F, r.gen(), r.gen(), r.gen(), r.gen()]
 }
 
-const DEFAULT_ADDR: &str = "127.0.0.1:6969";
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6969";
+pub const DEFAULT_BIND_ADDR: &str = "0.0.0.0:47589";
 
 impl WranglerSettings {
+    /// Default settings, without touching the config file.
+    pub fn new() -> Self {
+        Self {
+            addresses: vec![DEFAULT_ADDR.into()],
+            discover_server: false,
+            bind_address: return_bind_address(),
+            joycon: HashMap::new(),
+            send_reset: true,
+            send_full_reset: false,
+            send_mounting_reset: false,
+            emulated_mac: return_mac(),
+            keep_ids: false,
+            device_per_controller: false,
+            bundle_packets: true,
+            bindings: return_bindings(),
+            recording: None,
+        }
+    }
     pub fn save(&self) {
         let file = file_name().unwrap();
         if !file.exists() {
@@ -73,13 +365,7 @@ impl WranglerSettings {
         let settings = file_name()
             .and_then(|path| File::open(path).ok())
             .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
-            .unwrap_or_else(|| Self {
-                address: DEFAULT_ADDR.into(),
-                joycon: HashMap::new(),
-                send_reset: true,
-                emulated_mac: return_mac(),
-                keep_ids: false,
-            });
+            .unwrap_or_else(Self::new);
         settings.save();
         settings
     }
@@ -99,19 +385,140 @@ impl WranglerSettings {
             .get(serial_number)
             .map_or(1.0, |j| j.gyro_scale_factor)
     }
-    fn joycon_keep_id_set_new(&mut self, serial_number: String) {
-        let max = self.joycon.values().map(|j| j.keep_id).max();
+    pub fn joycon_gyro_bias_set(&mut self, serial_number: String, bias: [f64; 3]) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.gyro_bias = Some(bias);
+    }
+    pub fn joycon_gyro_bias_get(&self, serial_number: &str) -> [f64; 3] {
+        self.joycon
+            .get(serial_number)
+            .and_then(|j| j.gyro_bias)
+            .unwrap_or_default()
+    }
+    pub fn joycon_vqf_set(&mut self, serial_number: String, vqf: VqfParams) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.vqf = vqf;
+    }
+    pub fn joycon_vqf_get(&self, serial_number: &str) -> VqfParams {
+        self.joycon
+            .get(serial_number)
+            .map_or_else(VqfParams::default, |j| j.vqf)
+    }
+    pub fn joycon_filter_set(&mut self, serial_number: String, filter: FilterKind) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.filter = filter;
+    }
+    pub fn joycon_filter_get(&self, serial_number: &str) -> FilterKind {
+        self.joycon
+            .get(serial_number)
+            .map_or_else(FilterKind::default, |j| j.filter)
+    }
+    fn joycon_keep_id_set_new(&mut self, serial_number: String, freed: Option<u8>) {
+        // Ids of forgotten controllers are given out again, but not to the one that freed it
+        let mut used: BTreeSet<u8> = self.joycon.values().map(|j| j.keep_id).collect();
+        used.extend(freed);
+        let free = (1..u8::MAX).find(|id| !used.contains(id));
         let entry = self.joycon.entry(serial_number).or_default();
-        entry.keep_id = max.unwrap_or_default().saturating_add(1);
+        entry.keep_id = free.unwrap_or(u8::MAX);
         if entry.keep_id == u8::MAX {
             println!("\x1b[0;31m[ERROR]\x1b[0m TOO MANY JOYCONS SAVED! THIS WILL BREAK THINGS!");
-            println!(" YOU NEED TO DISABLE THE \"Save mounting location on server\" SETTING!!!");
+            println!(" YOU NEED TO FORGET SOME OF THE SAVED CONTROLLERS IN THE SETTINGS!!!");
+        }
+    }
+    /// Frees the id for other controllers, everything else saved for the controller stays.
+    pub fn joycon_keep_id_forget(&mut self, serial_number: &str) {
+        if let Some(joycon) = self.joycon.get_mut(serial_number) {
+            joycon.keep_id = 0;
+        }
+    }
+    pub fn joycon_keep_id_swap(&mut self, first: &str, second: &str) {
+        let (Some(a), Some(b)) = (
+            self.joycon.get(first).map(|j| j.keep_id),
+            self.joycon.get(second).map(|j| j.keep_id),
+        ) else {
+            return;
+        };
+        self.joycon.get_mut(first).unwrap().keep_id = b;
+        self.joycon.get_mut(second).unwrap().keep_id = a;
+    }
+    /// Renumbers the saved ids from 1 without gaps, keeping their order.
+    pub fn joycon_keep_ids_compact(&mut self) {
+        let mut joycons: Vec<_> = self
+            .joycon
+            .values_mut()
+            .filter(|j| j.keep_id != 0)
+            .collect();
+        joycons.sort_by_key(|j| j.keep_id);
+        for (id, joycon) in (1..).zip(joycons) {
+            joycon.keep_id = id;
+        }
+    }
+    pub fn joycon_last_seen_set(&mut self, serial_number: String) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.last_seen = Some(unix_time());
+    }
+    fn joycon_mac_set_new(&mut self, serial_number: String) {
+        let entry = self.joycon.entry(serial_number).or_default();
+        entry.emulated_mac = Some(return_mac());
+    }
+    pub fn binding_add(&mut self) {
+        self.bindings
+            .push(Binding::new(&[Button::Up], Action::Reset(ResetKind::Yaw)));
+    }
+    pub fn binding_remove(&mut self, index: usize) {
+        if index < self.bindings.len() {
+            self.bindings.remove(index);
+        }
+    }
+    pub fn binding_buttons_set(&mut self, index: usize, first: Button, second: Option<Button>) {
+        if let Some(binding) = self.bindings.get_mut(index) {
+            binding.buttons = [Some(first), second].into_iter().flatten().collect();
+        }
+    }
+    pub fn binding_long_press_set(&mut self, index: usize, long_press: bool) {
+        if let Some(binding) = self.bindings.get_mut(index) {
+            binding.long_press = long_press;
+        }
+    }
+    pub fn binding_action_set(&mut self, index: usize, action: Action) {
+        if let Some(binding) = self.bindings.get_mut(index) {
+            binding.action = action;
+        }
+    }
+    /// Every valid server address, without duplicates.
+    pub fn get_socket_addresses(&self) -> Vec<SocketAddr> {
+        self.addresses
+            .iter()
+            .filter_map(|a| a.parse::<SocketAddr>().ok())
+            .unique()
+            .collect()
+    }
+    /// The bind address, or the default one while it is invalid.
+    pub fn get_bind_address(&self) -> SocketAddr {
+        self.bind_address
+            .parse()
+            .unwrap_or_else(|_| DEFAULT_BIND_ADDR.parse().unwrap())
+    }
+    pub fn address_add(&mut self) {
+        self.addresses.push(String::new());
+    }
+    pub fn address_remove(&mut self, index: usize) {
+        if index < self.addresses.len() && self.addresses.len() > 1 {
+            self.addresses.remove(index);
         }
     }
-    pub fn get_socket_address(&self) -> SocketAddr {
-        self.address
-            .parse::<SocketAddr>()
-            .unwrap_or_else(|_| DEFAULT_ADDR.parse().unwrap())
+    pub fn address_set(&mut self, index: usize, address: String) {
+        if let Some(a) = self.addresses.get_mut(index) {
+            *a = address;
+        }
+    }
+    /// Replaces the address in the list with the one the server was discovered at.
+    pub fn address_replace(&mut self, configured: SocketAddr, address: SocketAddr) {
+        for a in &mut self.addresses {
+            if a.parse::<SocketAddr>().ok() == Some(configured) {
+                *a = address.to_string();
+            }
+        }
     }
 }
 impl Default for WranglerSettings {
@@ -120,11 +527,33 @@ impl Default for WranglerSettings {
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct Handler {
     arc: Arc<ArcSwap<WranglerSettings>>,
+    /// Held for a whole change, so changes from the GUI and the communication thread don't
+    /// overwrite each other. True while there are changes that aren't saved yet.
+    unsaved: Arc<Mutex<bool>>,
+    persist: bool,
+}
+impl Default for Handler {
+    fn default() -> Self {
+        Self {
+            arc: Arc::default(),
+            unsaved: Arc::default(),
+            persist: true,
+        }
+    }
 }
 impl Handler {
+    /// Settings that are never written to the config file.
+    #[cfg(test)]
+    pub fn in_memory(settings: WranglerSettings) -> Self {
+        Self {
+            arc: Arc::new(ArcSwap::from_pointee(settings)),
+            unsaved: Arc::default(),
+            persist: false,
+        }
+    }
     pub fn load(&self) -> Guard<Arc<WranglerSettings>> {
         self.arc.load()
     }
@@ -132,12 +561,37 @@ impl Handler {
     where
         T: FnOnce(&mut WranglerSettings),
     {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        let mut current = (**self.arc.load()).clone();
+        func(&mut current);
+        if self.persist {
+            current.save();
+        }
+        *unsaved = false;
+        self.arc.store(Arc::new(current));
+    }
+    /// For changes that happen all the time, like a controller connecting. They are saved with
+    /// the next `change` or `save_unsaved`.
+    pub fn change_unsaved<T>(&self, func: T)
+    where
+        T: FnOnce(&mut WranglerSettings),
+    {
+        let mut unsaved = self.unsaved.lock().unwrap();
         let mut current = (**self.arc.load()).clone();
         func(&mut current);
-        current.save();
+        *unsaved = true;
         self.arc.store(Arc::new(current));
     }
-    pub fn joycon_keep_id(&self, serial_number: String) -> u8 {
+    pub fn save_unsaved(&self) {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        if *unsaved && self.persist {
+            self.arc.load().save();
+        }
+        *unsaved = false;
+    }
+    /// The saved id of the controller, a new one if it has none. `freed` is the id a connected
+    /// controller was using before its id was forgotten, it gets a different one.
+    pub fn joycon_keep_id(&self, serial_number: String, freed: Option<u8>) -> u8 {
         let keep_id = self
             .load()
             .joycon
@@ -146,10 +600,23 @@ impl Handler {
         if keep_id != 0 {
             return keep_id;
         }
-        self.change(|ws| ws.joycon_keep_id_set_new(serial_number.clone()));
+        self.change(|ws| ws.joycon_keep_id_set_new(serial_number.clone(), freed));
         self.load()
             .joycon
             .get(&serial_number)
             .map_or(0, |j| j.keep_id)
     }
+    /// The MAC address the controller is emulated with, generated and saved the first time.
+    pub fn joycon_mac(&self, serial_number: String) -> [u8; 6] {
+        let mac = self
+            .load()
+            .joycon
+            .get(&serial_number)
+            .and_then(|j| j.emulated_mac);
+        if let Some(mac) = mac {
+            return mac;
+        }
+        self.change(|ws| ws.joycon_mac_set_new(serial_number.clone()));
+        self.load().joycon[&serial_number].emulated_mac.unwrap()
+    }
 }